@@ -0,0 +1,1640 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, BufReader},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crypto_box::{PublicKey, SecretKey};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    RsaPrivateKey, RsaPublicKey,
+};
+use thiserror::Error;
+
+use crate::{
+    device,
+    known_hosts::{HostKeyStatus, KnownHosts},
+    user_keys::UserKeys,
+    Paths,
+};
+use types::{
+    enc::{self, key_fingerprint, AesData, AesError, RsaData},
+    frame, CChannel, CPacket, CPresence, ChannelInfo, Credentials, DeliveryStatus, Device,
+    HandshakeTranscript, HistoryEntry, InboundMessage, MessageBody, OutboundMessage, Presence,
+    Receipt, RenameRequest, SAccount, SChannel, SError, SPacket, SPresence, SRecvMessage,
+    SequencedPacket, SessionInfo,
+};
+
+/// Largest packet accepted from the server, history pages are the biggest
+const MAX_PACKET_SIZE: u64 = 16 * 1024 * 1024;
+/// How long a session token is used before swapping it for a fresh one, so a token that leaked
+/// is not of use for long
+const TOKEN_REFRESH: Duration = Duration::from_secs(60 * 60);
+/// How long to wait for the server to accept the connection, and then for its handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to the server, see the [crate] docs for how it is used. Requests block until
+/// the server replies, so each connection handles one request at a time.
+pub struct Connection {
+    stream: TcpStream,
+    /// Shut down to stop the reader thread once `stream` is no longer usable
+    socket: TcpStream,
+    /// Cleared by the reader thread once the server hangs up
+    connected: Arc<AtomicBool>,
+    /// Counts the links opened so far, shared with the [`MessageReceiver`] so it can drop news
+    /// of a link dropping that only arrives once the next one is up
+    generation: Arc<AtomicU64>,
+    /// Replies to our requests, split out from pushed messages by the reader thread
+    responses: Receiver<SPacket>,
+    /// Messages, receipts and presence pushed by the server, handed to the [`MessageReceiver`].
+    /// Kept across reconnects, every reader thread gets a copy of `pushed`.
+    messages: Option<Receiver<Pushed>>,
+    pushed: Sender<Pushed>,
+    addr: String,
+    /// Fingerprint the server key must have, checked instead of the known hosts file
+    pinned: Option<String>,
+    version: u32,
+    capabilities: Vec<String>,
+    username: Option<String>,
+    /// What we logged in with, to log back in after reconnecting
+    credentials: Option<Credentials>,
+    /// The device we subscribed as, to subscribe again after reconnecting
+    device: Option<Device>,
+    /// Users whose presence we watch, watched again after reconnecting
+    watching: BTreeSet<String>,
+    away: bool,
+    token: u128,
+    /// When the server handed out `token`
+    token_issued: Instant,
+    token_refresh: Duration,
+    /// Set while logging back in and resubscribing, so a failing request doesn't start over
+    restoring: bool,
+    /// Sequence number of the last request we sent
+    seq: u64,
+    aes_key: Vec<u8>,
+    server_key: RsaPublicKey,
+    client_key: RsaPrivateKey,
+    user_keys: UserKeys,
+    paths: Paths,
+    /// Opens end-to-end encrypted messages sent to us, loaded once logged in. Shared with the
+    /// [`MessageReceiver`], so it can use a key imported later.
+    secret_key: Arc<RwLock<Option<SecretKey>>>,
+    /// Public keys of other users, looked up as needed
+    public_keys: HashMap<String, PublicKey>,
+}
+/// What the reader thread hands the [`MessageReceiver`]
+enum Pushed {
+    /// A packet pushed by the server, with the key of the connection it arrived on
+    Packet(SRecvMessage, Vec<u8>),
+    Event(Incoming),
+    /// The reader thread of the link with this generation stopped
+    Disconnected(u64),
+}
+/// A connection that completed the handshake, with a reader thread splitting pushed packets
+/// from replies
+struct Link {
+    stream: TcpStream,
+    socket: TcpStream,
+    connected: Arc<AtomicBool>,
+    responses: Receiver<SPacket>,
+    version: u32,
+    capabilities: Vec<String>,
+    token: u128,
+    aes_key: Vec<u8>,
+    server_key: RsaPublicKey,
+    client_key: RsaPrivateKey,
+}
+impl Link {
+    /// Connects and handshakes with `addr`, checking the server key against `pinned` or else
+    /// the known hosts file
+    fn open(
+        addr: &str,
+        pinned: Option<&str>,
+        known_hosts: &Path,
+        pushed: Sender<Pushed>,
+        generation: u64,
+    ) -> Result<Self, ConnectError> {
+        let unreachable = |e: io::Error| ConnectError::Unreachable(e.to_string());
+        let client = connect_timeout(addr).map_err(unreachable)?;
+        client.set_nonblocking(false).map_err(unreachable)?;
+        client
+            .set_read_timeout(Some(CONNECT_TIMEOUT))
+            .map_err(unreachable)?;
+        let mut stream = client.try_clone().map_err(unreachable)?;
+        let socket = client.try_clone().map_err(unreachable)?;
+        let mut reader = BufReader::new(client);
+        let priv_key = RsaPrivateKey::new(&mut OsRng, 2048)
+            .map_err(|_| ConnectError::Handshake(HandshakeError::KeyGeneration))?;
+        let handshake = CPacket::Handshake {
+            client_key: priv_key.to_public_key(),
+            version: types::PROTOCOL_VERSION,
+            capabilities: types::CAPABILITIES
+                .iter()
+                .map(|cap| cap.to_string())
+                .collect(),
+        };
+        frame::write(&mut stream, &handshake).map_err(|_| ConnectError::Disconnected)?;
+        let (server_key, version, min_version, capabilities, shared_key, token, signature) =
+            match frame::read(&mut reader, MAX_PACKET_SIZE) {
+                Ok(SPacket::Handshake {
+                    server_key,
+                    version,
+                    min_version,
+                    capabilities,
+                    shared_key,
+                    token,
+                    signature,
+                }) => (
+                    server_key,
+                    version,
+                    min_version,
+                    capabilities,
+                    shared_key,
+                    token,
+                    signature,
+                ),
+                Ok(SPacket::Error(SError::UnsupportedVersion { min, max })) => {
+                    return Err(ConnectError::UnsupportedVersion { min, max })
+                }
+                Ok(SPacket::Error(e)) => {
+                    return Err(ConnectError::Handshake(HandshakeError::Server(e)))
+                }
+                Ok(_) => return Err(ConnectError::Handshake(HandshakeError::UnexpectedPacket)),
+                Err(_) => return Err(ConnectError::Disconnected),
+            };
+        // Replies to requests take as long as they take from here on
+        reader
+            .get_ref()
+            .set_read_timeout(None)
+            .map_err(unreachable)?;
+        // The server picked the lower of both newest versions, so one below our range is the
+        // newest it speaks
+        if !(types::MIN_PROTOCOL_VERSION..=types::PROTOCOL_VERSION).contains(&version) {
+            return Err(ConnectError::UnsupportedVersion {
+                min: min_version,
+                max: version,
+            });
+        }
+
+        let fingerprint = key_fingerprint(&server_key);
+        // Known hosts to pin the key in once the server proves it holds it
+        let mut unpinned = None;
+        match pinned {
+            Some(pinned) if pinned != fingerprint => {
+                return Err(ConnectError::HostKeyMismatch {
+                    pinned: pinned.to_string(),
+                    presented: fingerprint,
+                })
+            }
+            Some(_) => {}
+            None => {
+                let known_hosts = KnownHosts::load(known_hosts.to_path_buf())
+                    .map_err(|e| ConnectError::KnownHosts(e.to_string()))?;
+                match known_hosts.check(addr, &fingerprint) {
+                    HostKeyStatus::Trusted => {}
+                    HostKeyStatus::Unknown => unpinned = Some(known_hosts),
+                    HostKeyStatus::Mismatch { pinned } => {
+                        return Err(ConnectError::HostKeyMismatch {
+                            pinned,
+                            presented: fingerprint,
+                        })
+                    }
+                }
+            }
+        }
+
+        // The key is one we trust or have never seen, but only its signature shows the server
+        // itself encrypted the session to our key
+        let transcript = HandshakeTranscript {
+            client_key: &priv_key.to_public_key(),
+            version,
+            min_version,
+            capabilities: &capabilities,
+            shared_key: &shared_key,
+            token: &token,
+        };
+        enc::verify(&transcript, &signature, &server_key)
+            .map_err(|_| ConnectError::Handshake(HandshakeError::Signature))?;
+        if let Some(mut known_hosts) = unpinned {
+            known_hosts
+                .pin(addr, &fingerprint)
+                .map_err(|e| ConnectError::KnownHosts(e.to_string()))?;
+        }
+        let token = token
+            .get(&priv_key)
+            .map_err(|_| ConnectError::Handshake(HandshakeError::Token))?;
+        let aes_key = shared_key
+            .get(&priv_key)
+            .map_err(|_| ConnectError::Handshake(HandshakeError::SharedKey))?;
+
+        let connected = Arc::new(AtomicBool::new(true));
+        let (responses_tx, responses) = mpsc::channel();
+        let reader_connected = connected.clone();
+        let reader_key = aes_key.clone();
+        std::thread::Builder::new()
+            .name("Connection reader".to_string())
+            .spawn(move || {
+                while let Ok(packet) = frame::read(&mut reader, MAX_PACKET_SIZE) {
+                    match packet {
+                        SPacket::RecvMessage(
+                            packet @ (SRecvMessage::NextMsg { .. }
+                            | SRecvMessage::Receipt { .. }
+                            | SRecvMessage::Presence { .. }),
+                        ) => {
+                            let _ = pushed.send(Pushed::Packet(packet, reader_key.clone()));
+                        }
+                        packet => {
+                            if responses_tx.send(packet).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                // Cleared before it is announced, so whoever hears of it can reconnect at once
+                reader_connected.store(false, Ordering::SeqCst);
+                let _ = pushed.send(Pushed::Disconnected(generation));
+            })
+            .unwrap();
+
+        Ok(Self {
+            stream,
+            socket,
+            connected,
+            responses,
+            version,
+            capabilities,
+            token,
+            aes_key,
+            server_key,
+            client_key: priv_key,
+        })
+    }
+}
+/// Like [`TcpStream::connect`], but gives up on each address `addr` resolves to after
+/// [`CONNECT_TIMEOUT`]
+fn connect_timeout(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Address did not resolve")))
+}
+impl Connection {
+    /// Connects and handshakes with `addr`, pinning the server key on first use and refusing
+    /// to continue if it differs from the key pinned for `addr` in the known hosts file.
+    pub fn new(addr: &str) -> Result<Self, ConnectError> {
+        Self::connect(addr, None, Paths::default())
+    }
+    /// Like [`Connection::new`], but keeps known hosts, keys and the device ID at `paths`
+    pub fn with_paths(addr: &str, paths: Paths) -> Result<Self, ConnectError> {
+        Self::connect(addr, None, paths)
+    }
+    /// Like [`Connection::new`], but requires the server key to have `fingerprint` rather than
+    /// checking the known hosts file
+    pub fn with_pinned_key(addr: &str, fingerprint: &str) -> Result<Self, ConnectError> {
+        Self::connect(addr, Some(fingerprint), Paths::default())
+    }
+    /// Like [`Connection::with_pinned_key`], but keeps keys and the device ID at `paths`. The
+    /// known hosts file is not used.
+    pub fn with_pinned_key_and_paths(
+        addr: &str,
+        fingerprint: &str,
+        paths: Paths,
+    ) -> Result<Self, ConnectError> {
+        Self::connect(addr, Some(fingerprint), paths)
+    }
+    fn connect(addr: &str, pinned: Option<&str>, paths: Paths) -> Result<Self, ConnectError> {
+        let (pushed, messages) = mpsc::channel();
+        let link = Link::open(addr, pinned, &paths.known_hosts, pushed.clone(), 0)?;
+        Ok(Self {
+            stream: link.stream,
+            socket: link.socket,
+            connected: link.connected,
+            generation: Arc::new(AtomicU64::new(0)),
+            responses: link.responses,
+            messages: Some(messages),
+            pushed,
+            addr: addr.to_string(),
+            pinned: pinned.map(str::to_string),
+            version: link.version,
+            capabilities: link.capabilities,
+            username: None,
+            credentials: None,
+            device: None,
+            watching: BTreeSet::new(),
+            away: false,
+            token: link.token,
+            token_issued: Instant::now(),
+            token_refresh: TOKEN_REFRESH,
+            restoring: false,
+            seq: 0,
+            aes_key: link.aes_key,
+            server_key: link.server_key,
+            client_key: link.client_key,
+            user_keys: UserKeys::new(paths.user_keys.clone()),
+            paths,
+            secret_key: Arc::new(RwLock::new(None)),
+            public_keys: HashMap::new(),
+        })
+    }
+    /// Whether the connection to the server is still up, see [`Connection::reconnect`]
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+    /// Connects to the server again once the connection dropped, then logs back in,
+    /// resubscribes and watches the same users as before. The [`MessageReceiver`] keeps
+    /// working and gets [`Incoming::Reconnected`] once done. Does nothing while connected.
+    pub fn reconnect(&mut self) -> Result<(), ConnectError> {
+        if self.is_connected() {
+            return Ok(());
+        }
+        self.relink()
+    }
+    /// Opens a new link in place of the current one and restores the session on it
+    fn relink(&mut self) -> Result<(), ConnectError> {
+        let generation = self.generation.load(Ordering::SeqCst) + 1;
+        let link = Link::open(
+            &self.addr,
+            self.pinned.as_deref(),
+            &self.paths.known_hosts,
+            self.pushed.clone(),
+            generation,
+        )?;
+        self.generation.store(generation, Ordering::SeqCst);
+        self.stream = link.stream;
+        self.socket = link.socket;
+        self.connected = link.connected;
+        self.responses = link.responses;
+        self.version = link.version;
+        self.capabilities = link.capabilities;
+        self.token = link.token;
+        self.token_issued = Instant::now();
+        self.seq = 0;
+        self.aes_key = link.aes_key;
+        self.server_key = link.server_key;
+        self.client_key = link.client_key;
+        self.username = None;
+
+        self.restoring = true;
+        let restored = self.restore_session();
+        self.restoring = false;
+        restored
+    }
+    fn restore_session(&mut self) -> Result<(), ConnectError> {
+        if let Some(credentials) = self.credentials.clone() {
+            self.login_with(credentials)
+                .map_err(ConnectError::Relogin)?;
+            let watching: Vec<String> = self.watching.iter().cloned().collect();
+            if !watching.is_empty() {
+                let presence = self
+                    .watch_presence(watching)
+                    .map_err(|e| ConnectError::Resubscribe(e.to_string()))?;
+                for presence in presence {
+                    let _ = self
+                        .pushed
+                        .send(Pushed::Event(Incoming::Presence(presence)));
+                }
+            }
+            if self.away {
+                self.set_away(true)
+                    .map_err(|e| ConnectError::Resubscribe(e.to_string()))?;
+            }
+        }
+        if let Some(device) = self.device.clone() {
+            self.request_subscription(device)
+                .map_err(|e| ConnectError::Resubscribe(e.to_string()))?;
+            let _ = self.pushed.send(Pushed::Event(Incoming::Reconnected));
+        }
+        Ok(())
+    }
+    /// Calls [`Connection::reconnect`] until it succeeds, waiting longer after each failed
+    /// attempt. Gives up on errors retrying won't fix, such as a changed password.
+    pub fn reconnect_with_backoff(&mut self) -> Result<(), ConnectError> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.reconnect() {
+                Err(e) if e.is_transient() => std::thread::sleep(backoff.next_delay()),
+                result => return result,
+            }
+        }
+    }
+    /// The protocol version negotiated with the server
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    /// Who we are logged in as
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+    /// Whether both we and the server support the optional feature `capability`
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap == capability)
+    }
+    /// Logs in as `username`, publishing a key for end-to-end encrypted messages if this device
+    /// has none stored yet
+    pub fn login(&mut self, username: String, password: &str) -> Result<(), LoginError> {
+        self.login_with(Credentials {
+            username,
+            pw_digest: sha256::digest(password),
+        })
+    }
+    fn login_with(&mut self, credentials: Credentials) -> Result<(), LoginError> {
+        let creds = AesData::new(credentials.clone(), &self.aes_key)
+            .map_err(|_| LoginError::EncryptionFailed)?;
+        self.send(CPacket::Account(types::CAccount::Login { creds }))?;
+        match self.read() {
+            Ok(SPacket::Account(SAccount::Success)) => {
+                self.username = Some(credentials.username.clone());
+                self.credentials = Some(credentials);
+                self.sync_key()
+            }
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(LoginError::InvalidToken)
+            }
+            Ok(SPacket::Account(SAccount::IncorrectPassword)) => Err(LoginError::IncorrectPassword),
+            Ok(SPacket::Error(e)) => Err(LoginError::Server(e)),
+            Ok(_) => Err(LoginError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Loads our secret key if it matches the public key the account published. The first
+    /// device to log in to an account without a key generates and publishes one. Any other
+    /// device without the key has to import it, see [`Connection::import_key`], as replacing
+    /// it would leave the account's other devices unable to read new messages.
+    fn sync_key(&mut self) -> Result<(), LoginError> {
+        let username = self.username.clone().unwrap();
+        let stored = self
+            .user_keys
+            .load(&self.addr, &username)
+            .map_err(|e| LoginError::KeyStore(e.to_string()))?;
+        let secret_key = match (stored, self.public_key(&username)?) {
+            (Some(key), Some(published)) if key.public_key() == published => Some(key),
+            // Replaced from another device, or never stored here
+            (_, Some(_)) => None,
+            (stored, None) => {
+                let key = match stored {
+                    Some(key) => key,
+                    None => self
+                        .user_keys
+                        .generate(&self.addr, &username)
+                        .map_err(|e| LoginError::KeyStore(e.to_string()))?,
+                };
+                self.publish_key(&key.public_key())?;
+                Some(key)
+            }
+        };
+        *self.secret_key.write().unwrap() = secret_key;
+        Ok(())
+    }
+    fn publish_key(&mut self, public_key: &PublicKey) -> Result<(), SessionError> {
+        match self.request(|_| {
+            Ok(CPacket::Account(types::CAccount::PublishKey {
+                public_key: *public_key.as_bytes(),
+            }))
+        }) {
+            Ok(SPacket::Account(SAccount::Success)) => {
+                let username = self.username.clone().unwrap_or_default();
+                self.public_keys.insert(username, public_key.clone());
+                Ok(())
+            }
+            Ok(packet) => Err(self.session_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Fingerprint of the key end-to-end encrypted messages to `username` are sealed to, to
+    /// compare with the one they see over some other channel. `None` if they have no key.
+    pub fn fingerprint(&mut self, username: &str) -> Result<Option<String>, SessionError> {
+        Ok(self
+            .public_key(username)?
+            .map(|key| sha256::digest(key.as_bytes())))
+    }
+    /// Pins the key `username` has now in place of the one pinned before, after they changed
+    /// it. Compare fingerprints with them first, see [`Connection::fingerprint`].
+    pub fn trust_key(&mut self, username: &str) -> Result<(), KeyError> {
+        self.public_keys.remove(username);
+        let Some(fingerprint) = self.fingerprint(username)? else {
+            return Err(KeyError::NoPublicKey(username.to_string()));
+        };
+        KnownHosts::load(self.paths.known_users.clone())
+            .and_then(|mut known_users| {
+                known_users.pin(&format!("{username}@{}", self.addr), &fingerprint)
+            })
+            .map_err(|e| KeyError::KnownUsers(e.to_string()))
+    }
+    /// Checks the key of `username` against the one pinned for them, pinning it the first time
+    fn check_pinned(&self, username: &str, public_key: &PublicKey) -> Result<(), SendMessageError> {
+        let name = format!("{username}@{}", self.addr);
+        let fingerprint = sha256::digest(public_key.as_bytes());
+        let known_users_error = |e: io::Error| SendMessageError::KnownUsers(e.to_string());
+        let mut known_users =
+            KnownHosts::load(self.paths.known_users.clone()).map_err(known_users_error)?;
+        match known_users.check(&name, &fingerprint) {
+            HostKeyStatus::Trusted => Ok(()),
+            HostKeyStatus::Unknown => known_users
+                .pin(&name, &fingerprint)
+                .map_err(known_users_error),
+            HostKeyStatus::Mismatch { pinned } => Err(SendMessageError::KeyChanged {
+                username: username.to_string(),
+                pinned,
+                presented: fingerprint,
+            }),
+        }
+    }
+    /// Our secret key, to move to another device of ours with [`Connection::import_key`].
+    /// `None` if this device doesn't hold the key the account published either.
+    pub fn export_key(&self) -> Option<[u8; 32]> {
+        self.secret_key
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(SecretKey::to_bytes)
+    }
+    /// Stores a secret key exported on another device of ours, so this one can open end-to-end
+    /// encrypted messages too. Only accepts the key the account published.
+    pub fn import_key(&mut self, key: [u8; 32]) -> Result<(), KeyError> {
+        let Some(username) = self.username.clone() else {
+            return Err(KeyError::NotLoggedIn);
+        };
+        let key = SecretKey::from(key);
+        // Looked up again, the key may have been replaced since we logged in
+        self.public_keys.remove(&username);
+        if self.public_key(&username)? != Some(key.public_key()) {
+            return Err(KeyError::WrongKey);
+        }
+        self.user_keys
+            .save(&self.addr, &username, &key)
+            .map_err(|e| KeyError::KeyStore(e.to_string()))?;
+        *self.secret_key.write().unwrap() = Some(key);
+        Ok(())
+    }
+    /// Replaces the account's key with a fresh one, for when none of our devices holds it any
+    /// more. Our other devices have to import the new key, and messages sealed to the old one
+    /// can't be opened with it.
+    pub fn reset_key(&mut self) -> Result<(), KeyError> {
+        let Some(username) = self.username.clone() else {
+            return Err(KeyError::NotLoggedIn);
+        };
+        let key = SecretKey::generate(&mut OsRng);
+        self.publish_key(&key.public_key())?;
+        self.user_keys
+            .save(&self.addr, &username, &key)
+            .map_err(|e| KeyError::KeyStore(e.to_string()))?;
+        *self.secret_key.write().unwrap() = Some(key);
+        Ok(())
+    }
+    /// Looks up the key end-to-end encrypted messages to `username` are sealed to
+    pub fn public_key(&mut self, username: &str) -> Result<Option<PublicKey>, SessionError> {
+        if let Some(public_key) = self.public_keys.get(username) {
+            return Ok(Some(public_key.clone()));
+        }
+        match self.request(|key| {
+            Ok(CPacket::Account(types::CAccount::LookupKey {
+                username: AesData::new(username.to_string(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Account(SAccount::PublicKey { public_key })) => {
+                let public_key = public_key.map(PublicKey::from);
+                if let Some(public_key) = &public_key {
+                    self.public_keys
+                        .insert(username.to_string(), public_key.clone());
+                }
+                Ok(public_key)
+            }
+            Ok(SPacket::Account(SAccount::NotLoggedIn)) => Err(SessionError::NotLoggedIn),
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(SessionError::InvalidToken)
+            }
+            Ok(SPacket::Error(e)) => Err(SessionError::Server(e)),
+            Ok(_) => Err(SessionError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Swaps our session token for a fresh one, the old one stops working. Done before any
+    /// request once the token is older than [`Connection::set_token_refresh`] allows. The session
+    /// still ends once the server's token lifetime is up, and is then logged back into.
+    pub fn refresh_token(&mut self) -> Result<(), SessionError> {
+        self.send_sequenced(CPacket::Account(types::CAccount::RefreshToken))?;
+        match self.read() {
+            Ok(SPacket::Account(SAccount::Token { token })) => {
+                self.token = token
+                    .get(&self.client_key)
+                    .map_err(|_| SessionError::InvalidPacket)?;
+                self.token_issued = Instant::now();
+                Ok(())
+            }
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(SessionError::InvalidToken)
+            }
+            Ok(SPacket::Error(e)) => Err(SessionError::Server(e)),
+            Ok(_) => Err(SessionError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Sets how long a session token is used before it is refreshed, an hour by default
+    pub fn set_token_refresh(&mut self, interval: Duration) {
+        self.token_refresh = interval;
+    }
+    /// Returns the current presence of `usernames`, after which changes to it arrive as
+    /// [`Incoming::Presence`]. Users that don't exist are left out. The server forgets our
+    /// watches once we go offline.
+    pub fn watch_presence(
+        &mut self,
+        usernames: Vec<String>,
+    ) -> Result<Vec<Presence>, SessionError> {
+        match self.request(|key| {
+            Ok(CPacket::Presence(CPresence::Watch {
+                usernames: AesData::new(usernames.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Presence(SPresence::Watching { presence })) => {
+                let presence: Vec<Presence> = presence
+                    .get(&self.aes_key)
+                    .map_err(|_| SessionError::InvalidPacket)?;
+                self.watching
+                    .extend(presence.iter().map(|presence| presence.username.clone()));
+                Ok(presence)
+            }
+            Ok(packet) => Err(self.session_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Stops sending presence changes of `usernames`
+    pub fn unwatch_presence(&mut self, usernames: Vec<String>) -> Result<(), SessionError> {
+        match self.request(|key| {
+            Ok(CPacket::Presence(CPresence::Unwatch {
+                usernames: AesData::new(usernames.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Presence(SPresence::Success)) => {
+                for username in &usernames {
+                    self.watching.remove(username);
+                }
+                Ok(())
+            }
+            Ok(packet) => Err(self.session_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Marks this connection as away, we show as away to others once all our connections are
+    pub fn set_away(&mut self, away: bool) -> Result<(), SessionError> {
+        match self.request(|_| Ok(CPacket::Presence(CPresence::SetAway { away }))) {
+            Ok(SPacket::Presence(SPresence::Success)) => {
+                self.away = away;
+                Ok(())
+            }
+            Ok(packet) => Err(self.session_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Changes our password, the connection stays logged in
+    pub fn change_password(&mut self, old: &str, new: &str) -> Result<(), AccountError> {
+        let new = sha256::digest(new);
+        match self.request(|key| {
+            Ok(CPacket::Account(types::CAccount::ChangePassword {
+                old: AesData::new(sha256::digest(old), key)?,
+                new: AesData::new(new.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Account(SAccount::Success)) => {
+                if let Some(credentials) = &mut self.credentials {
+                    credentials.pw_digest = new;
+                }
+                Ok(())
+            }
+            Ok(packet) => Err(self.account_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Deletes our account and everything the server stores for it. The connection stays open
+    /// but is logged out.
+    pub fn delete_account(&mut self, password: &str) -> Result<(), AccountError> {
+        match self.request(|key| {
+            Ok(CPacket::Account(types::CAccount::DeleteAccount {
+                pw_digest: AesData::new(sha256::digest(password), key)?,
+            }))
+        }) {
+            Ok(SPacket::Account(SAccount::Success)) => {
+                if let Some(username) = self.username.take() {
+                    self.public_keys.remove(&username);
+                }
+                self.credentials = None;
+                self.watching.clear();
+                self.away = false;
+                *self.secret_key.write().unwrap() = None;
+                Ok(())
+            }
+            Ok(packet) => Err(self.account_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Asks an admin to rename our account. Once approved we are logged out everywhere and log
+    /// in under the new name. Our key is stored under the new name too, so end-to-end encrypted
+    /// messages stay readable after the rename.
+    pub fn request_rename(&mut self, new_username: String) -> Result<(), AccountError> {
+        match self.request(|key| {
+            Ok(CPacket::Account(types::CAccount::RequestRename {
+                new_username: AesData::new(new_username.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Account(SAccount::Success)) => {}
+            Ok(packet) => return Err(self.account_error(packet)),
+            Err(e) => return Err(e.into()),
+        }
+        let Some(secret_key) = self.secret_key.read().unwrap().clone() else {
+            return Ok(());
+        };
+        match self.user_keys.load(&self.addr, &new_username) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => self
+                .user_keys
+                .save(&self.addr, &new_username, &secret_key)
+                .map_err(|e| AccountError::KeyStore(e.to_string())),
+            Err(e) => Err(AccountError::KeyStore(e.to_string())),
+        }
+    }
+    /// Pending rename requests, only available to admins
+    pub fn rename_requests(&mut self) -> Result<Vec<RenameRequest>, AccountError> {
+        match self.request(|_| Ok(CPacket::Account(types::CAccount::ListRenames))) {
+            Ok(SPacket::Account(SAccount::RenameRequests { requests })) => requests
+                .get(&self.aes_key)
+                .map_err(|_| AccountError::InvalidPacket),
+            Ok(packet) => Err(self.account_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Approves or rejects the pending request to rename `username`, only available to admins
+    pub fn review_rename(&mut self, username: String, approve: bool) -> Result<(), AccountError> {
+        match self.request(|key| {
+            Ok(CPacket::Account(types::CAccount::ReviewRename {
+                username: AesData::new(username.clone(), key)?,
+                approve,
+            }))
+        }) {
+            Ok(SPacket::Account(SAccount::Success)) => Ok(()),
+            Ok(packet) => Err(self.account_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Every session logged in as us, including this one
+    pub fn sessions(&mut self) -> Result<Vec<SessionInfo>, AccountError> {
+        match self.request(|_| Ok(CPacket::Account(types::CAccount::ListSessions))) {
+            Ok(SPacket::Account(SAccount::Sessions { sessions })) => sessions
+                .get(&self.aes_key)
+                .map_err(|_| AccountError::InvalidPacket),
+            Ok(packet) => Err(self.account_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Logs out one of our other sessions, see [`Connection::sessions`]. A client still holding
+    /// the password logs straight back in, change it to keep a device out for good.
+    pub fn revoke_session(&mut self, id: u64) -> Result<(), AccountError> {
+        match self.request(|_| Ok(CPacket::Account(types::CAccount::RevokeSession { id }))) {
+            Ok(SPacket::Account(SAccount::Success)) => Ok(()),
+            Ok(packet) => Err(self.account_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Creates an account, then a fresh key for end-to-end encrypted messages stored on this
+    /// device. The key is published the first time the account logs in. Does not log in.
+    pub fn create_account(
+        &mut self,
+        username: String,
+        password: &str,
+    ) -> Result<(), CreateAccountError> {
+        match self.request(|key| {
+            Ok(CPacket::Account(types::CAccount::Create {
+                creds: AesData::new(
+                    Credentials {
+                        username: username.clone(),
+                        pw_digest: sha256::digest(password),
+                    },
+                    key,
+                )?,
+            }))
+        }) {
+            // Only once the name is ours, so a failed attempt leaves no key behind for an
+            // account someone else holds. One left over from a deleted account is replaced.
+            Ok(SPacket::Account(SAccount::Success)) => self
+                .user_keys
+                .generate(&self.addr, &username)
+                .map(drop)
+                .map_err(|e| CreateAccountError::KeyStore(e.to_string())),
+            Ok(SPacket::Account(SAccount::AccountExists)) => Err(CreateAccountError::AccountExists),
+            Ok(SPacket::Account(SAccount::InvalidUsername)) => {
+                Err(CreateAccountError::InvalidUsername)
+            }
+            Ok(SPacket::Account(SAccount::TooManyAccounts)) => {
+                Err(CreateAccountError::TooManyAccounts)
+            }
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(CreateAccountError::InvalidToken)
+            }
+            Ok(SPacket::Error(e)) => Err(CreateAccountError::Server(e)),
+            Ok(_) => Err(CreateAccountError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Sends `contents` as is to users and channels, see
+    /// [`Connection::send_encrypted_message`] for direct messages the server can't read
+    pub fn send_message(
+        &mut self,
+        recipients: Vec<String>,
+        contents: String,
+    ) -> Result<SentMessage, SendMessageError> {
+        self.send_body(recipients, MessageBody::Plain(contents))
+    }
+    /// Seals `contents` to each recipient's public key so the server can't read it. Only works
+    /// for direct messages, as the server can't fan sealed messages out to channels. Recipients
+    /// without an account are reported in [`SentMessage::rejected`] rather than failing the send.
+    pub fn send_encrypted_message(
+        &mut self,
+        recipients: Vec<String>,
+        contents: String,
+    ) -> Result<SentMessage, SendMessageError> {
+        let mut boxes = BTreeMap::new();
+        for recipient in &recipients {
+            if types::is_channel_name(recipient) {
+                return Err(SendMessageError::SealedToChannel(recipient.clone()));
+            }
+            // Either no such user, or one who never published a key, the server tells us which
+            let Some(public_key) = self.public_key(recipient)? else {
+                continue;
+            };
+            self.check_pinned(recipient, &public_key)?;
+            let sealed = public_key
+                .seal(&mut OsRng, contents.as_bytes())
+                .map_err(|_| SendMessageError::EncryptionFailed)?;
+            boxes.insert(recipient.clone(), sealed);
+        }
+        // Our own copy, so the message can be read back from history
+        let secret_key = self.secret_key.read().unwrap().clone();
+        if let (Some(username), Some(secret_key)) = (&self.username, secret_key) {
+            if let Ok(sealed) = secret_key
+                .public_key()
+                .seal(&mut OsRng, contents.as_bytes())
+            {
+                boxes.entry(username.clone()).or_insert(sealed);
+            }
+        }
+        self.send_body(recipients, MessageBody::Sealed(boxes))
+    }
+    fn send_body(
+        &mut self,
+        recipients: Vec<String>,
+        contents: MessageBody,
+    ) -> Result<SentMessage, SendMessageError> {
+        match self.request(|key| {
+            Ok(CPacket::SendMessage(types::CSendMessage::Send {
+                message: AesData::new(
+                    OutboundMessage {
+                        recipients: recipients.clone(),
+                        contents: contents.clone(),
+                    },
+                    key,
+                )?,
+            }))
+        }) {
+            Ok(SPacket::SendMessage(types::SSendMessage::Success { id, statuses })) => {
+                Ok(SentMessage { id, statuses })
+            }
+            Ok(SPacket::SendMessage(types::SSendMessage::NotInChannel { channel })) => {
+                Err(SendMessageError::NotInChannel(channel))
+            }
+            Ok(SPacket::SendMessage(types::SSendMessage::QueueFull { recipient })) => {
+                Err(SendMessageError::QueueFull(recipient))
+            }
+            Ok(SPacket::SendMessage(types::SSendMessage::SealedToChannel { channel })) => {
+                Err(SendMessageError::SealedToChannel(channel))
+            }
+            Ok(SPacket::SendMessage(types::SSendMessage::MissingSealedBox { recipient })) => {
+                Err(SendMessageError::NoPublicKey(recipient))
+            }
+            Ok(SPacket::Account(SAccount::NotLoggedIn)) => Err(SendMessageError::NotLoggedIn),
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(SendMessageError::InvalidToken)
+            }
+            Ok(SPacket::Error(e)) => Err(SendMessageError::Server(e)),
+            Ok(_) => Err(SendMessageError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Asks the server to push our messages, which are then read from the returned receiver.
+    /// Can only be called once per connection.
+    pub fn subscribe(&mut self) -> Result<MessageReceiver, RecvMessageError> {
+        let device = device::load_or_create(&self.paths.device)
+            .map_err(|e| RecvMessageError::DeviceStore(e.to_string()))?;
+        self.subscribe_as(device)
+    }
+    /// Like [`Connection::subscribe`], but as `device` rather than the device stored on disk.
+    /// Each device is sent every message, sessions sharing a device share its place in the queue.
+    pub fn subscribe_as(&mut self, device: Device) -> Result<MessageReceiver, RecvMessageError> {
+        let Some(messages) = self.messages.take() else {
+            return Err(RecvMessageError::AlreadySubscribed);
+        };
+        // Nothing is pushed before subscribing, this only drops news of reconnects nobody was
+        // listening for
+        while messages.try_recv().is_ok() {}
+        match self.request_subscription(device) {
+            Ok(()) => Ok(MessageReceiver {
+                messages,
+                generation: self.generation.clone(),
+                secret_key: self.secret_key.clone(),
+            }),
+            Err(e) => {
+                self.messages = Some(messages);
+                Err(e)
+            }
+        }
+    }
+    fn request_subscription(&mut self, device: Device) -> Result<(), RecvMessageError> {
+        let devices = self.has_capability("devices");
+        match self.request(|key| {
+            Ok(CPacket::RecvMessage(if devices {
+                types::CRecvMessage::SubscribeDevice {
+                    device: AesData::new(device.clone(), key)?,
+                }
+            } else {
+                types::CRecvMessage::Subscribe
+            }))
+        }) {
+            Ok(SPacket::RecvMessage(SRecvMessage::Subscribed)) => {
+                self.device = Some(device);
+                Ok(())
+            }
+            Ok(SPacket::Account(SAccount::NotLoggedIn)) => Err(RecvMessageError::NotLoggedIn),
+            Ok(SPacket::Error(e)) => Err(RecvMessageError::Server(e)),
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(RecvMessageError::InvalidToken)
+            }
+            Ok(_) => Err(RecvMessageError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Fetches up to `limit` messages exchanged with a user or channel, oldest first. Pass the
+    /// index of the oldest entry fetched so far as `before` to page further back.
+    pub fn history(
+        &mut self,
+        peer_or_channel: String,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<HistoryEntry>, RecvMessageError> {
+        match self.request(|key| {
+            Ok(CPacket::RecvMessage(types::CRecvMessage::History {
+                peer_or_channel: AesData::new(peer_or_channel.clone(), key)?,
+                before,
+                limit,
+            }))
+        }) {
+            Ok(SPacket::RecvMessage(SRecvMessage::History { messages })) => {
+                match messages.get(&self.aes_key) {
+                    Ok(messages) => Ok(messages),
+                    Err(AesError::AuthenticationFailed) => {
+                        Err(RecvMessageError::AuthenticationFailed)
+                    }
+                    Err(_) => Err(RecvMessageError::DeserializationError),
+                }
+            }
+            Ok(SPacket::Account(SAccount::NotLoggedIn)) => Err(RecvMessageError::NotLoggedIn),
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(RecvMessageError::InvalidToken)
+            }
+            Ok(SPacket::Error(e)) => Err(RecvMessageError::Server(e)),
+            Ok(_) => Err(RecvMessageError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Tells the senders of the direct messages `ids` that we have read them
+    pub fn mark_read(&mut self, ids: Vec<u64>) -> Result<(), RecvMessageError> {
+        match self.request(|key| {
+            Ok(CPacket::RecvMessage(types::CRecvMessage::Read {
+                ids: AesData::new(ids.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::RecvMessage(SRecvMessage::Acknowledged)) => Ok(()),
+            Ok(SPacket::Account(SAccount::NotLoggedIn)) => Err(RecvMessageError::NotLoggedIn),
+            Ok(SPacket::Account(SAccount::InvalidToken)) => {
+                self.username = None;
+                Err(RecvMessageError::InvalidToken)
+            }
+            Ok(SPacket::Error(e)) => Err(RecvMessageError::Server(e)),
+            Ok(_) => Err(RecvMessageError::InvalidPacket),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Returns the text of a message, opening it if it was end-to-end encrypted
+    pub fn open(&self, contents: &MessageBody) -> Result<String, RecvMessageError> {
+        open_body(contents, self.secret_key.read().unwrap().as_ref())
+    }
+    /// Joins `channel`, creating it if nobody is in it yet
+    pub fn join_channel(&mut self, channel: String) -> Result<(), ChannelError> {
+        match self.request(|key| {
+            Ok(CPacket::Channel(CChannel::Join {
+                channel: AesData::new(channel.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Channel(SChannel::Success)) => Ok(()),
+            Ok(SPacket::Channel(SChannel::AlreadyInChannel)) => Err(ChannelError::AlreadyInChannel),
+            Ok(packet) => Err(self.channel_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Leaves `channel`, which is deleted once its last member leaves
+    pub fn part_channel(&mut self, channel: String) -> Result<(), ChannelError> {
+        match self.request(|key| {
+            Ok(CPacket::Channel(CChannel::Part {
+                channel: AesData::new(channel.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Channel(SChannel::Success)) => Ok(()),
+            Ok(packet) => Err(self.channel_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Every channel with its topic and members
+    pub fn list_channels(&mut self) -> Result<Vec<ChannelInfo>, ChannelError> {
+        match self.request(|_| Ok(CPacket::Channel(CChannel::List))) {
+            Ok(SPacket::Channel(SChannel::List { channels })) => channels
+                .get(&self.aes_key)
+                .map_err(|_| ChannelError::DeserializationError),
+            Ok(packet) => Err(self.channel_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Sets the channel topic if `topic` is `Some`, and returns the topic now in effect
+    pub fn channel_topic(
+        &mut self,
+        channel: String,
+        topic: Option<String>,
+    ) -> Result<Option<String>, ChannelError> {
+        match self.request(|key| {
+            Ok(CPacket::Channel(CChannel::Topic {
+                channel: AesData::new(channel.clone(), key)?,
+                topic: AesData::new(topic.clone(), key)?,
+            }))
+        }) {
+            Ok(SPacket::Channel(SChannel::Topic { topic })) => topic
+                .get(&self.aes_key)
+                .map_err(|_| ChannelError::DeserializationError),
+            Ok(packet) => Err(self.channel_error(packet)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Sends the request `build` makes under our shared key and waits for the reply. A logged
+    /// in session the server dropped, say after it sat idle, is restored on a new link and the
+    /// request is sent again under the new key.
+    fn request(
+        &mut self,
+        build: impl Fn(&[u8]) -> Result<CPacket, AesError>,
+    ) -> Result<SPacket, RequestError> {
+        let packet = build(&self.aes_key)?;
+        self.send(packet)?;
+        let reply = self.read()?;
+        if !matches!(reply, SPacket::Account(SAccount::InvalidToken))
+            || self.credentials.is_none()
+            || self.restoring
+        {
+            return Ok(reply);
+        }
+        // The old link may still be up, but is no use without a session
+        let _ = self.socket.shutdown(Shutdown::Both);
+        if self.relink().is_err() {
+            return Ok(reply);
+        }
+        let packet = build(&self.aes_key)?;
+        self.send(packet)?;
+        self.read()
+    }
+    /// Like [`Connection::send_sequenced`], but refreshes the session token first once it is due
+    fn send(&mut self, packet: CPacket) -> Result<(), RequestError> {
+        if self.username.is_some()
+            && !self.restoring
+            && self.token_issued.elapsed() >= self.token_refresh
+        {
+            // If this fails, the request that follows fails the same way and restores the session
+            let _ = self.refresh_token();
+        }
+        self.send_sequenced(packet)
+    }
+    /// Sends `packet` wrapped with the next sequence number
+    fn send_sequenced(&mut self, packet: CPacket) -> Result<(), RequestError> {
+        self.seq += 1;
+        let packet = CPacket::Sequenced {
+            token: RsaData::new(self.token, &self.server_key)
+                .map_err(|_| RequestError::Encryption)?,
+            packet: AesData::new(
+                SequencedPacket {
+                    seq: self.seq,
+                    packet,
+                },
+                &self.aes_key,
+            )?,
+        };
+        if frame::write(&mut self.stream, &packet).is_err() {
+            // Stops the reader thread, so waiting for the reply fails rather than hangs
+            let _ = self.socket.shutdown(Shutdown::Both);
+        }
+        Ok(())
+    }
+    fn read(&mut self) -> Result<SPacket, RequestError> {
+        self.responses
+            .recv()
+            .map_err(|_| RequestError::Disconnected)
+    }
+    fn session_error(&mut self, packet: SPacket) -> SessionError {
+        match packet {
+            SPacket::Account(SAccount::NotLoggedIn) => SessionError::NotLoggedIn,
+            SPacket::Error(e) => SessionError::Server(e),
+            SPacket::Account(SAccount::InvalidToken) => {
+                self.username = None;
+                SessionError::InvalidToken
+            }
+            _ => SessionError::InvalidPacket,
+        }
+    }
+    fn account_error(&mut self, packet: SPacket) -> AccountError {
+        match packet {
+            SPacket::Account(SAccount::IncorrectPassword) => AccountError::IncorrectPassword,
+            SPacket::Account(SAccount::InvalidUsername) => AccountError::InvalidUsername,
+            SPacket::Account(SAccount::AccountExists) => AccountError::AccountExists,
+            SPacket::Account(SAccount::NotAdmin) => AccountError::NotAdmin,
+            SPacket::Account(SAccount::NoRenameRequest) => AccountError::NoRenameRequest,
+            SPacket::Account(SAccount::NoSuchSession) => AccountError::NoSuchSession,
+            SPacket::Account(SAccount::NotLoggedIn) => AccountError::NotLoggedIn,
+            SPacket::Error(e) => AccountError::Server(e),
+            SPacket::Account(SAccount::InvalidToken) => {
+                self.username = None;
+                AccountError::InvalidToken
+            }
+            _ => AccountError::InvalidPacket,
+        }
+    }
+    fn channel_error(&mut self, packet: SPacket) -> ChannelError {
+        match packet {
+            SPacket::Channel(SChannel::InvalidChannel) => ChannelError::InvalidChannel,
+            SPacket::Channel(SChannel::NotInChannel) => ChannelError::NotInChannel,
+            SPacket::Account(SAccount::NotLoggedIn) => ChannelError::NotLoggedIn,
+            SPacket::Error(e) => ChannelError::Server(e),
+            SPacket::Account(SAccount::InvalidToken) => {
+                self.username = None;
+                ChannelError::InvalidToken
+            }
+            _ => ChannelError::InvalidPacket,
+        }
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+/// Delays between attempts to reconnect, doubling up to half a minute. Each delay is cut by a
+/// random amount, so clients dropped at once don't all come back at once.
+pub struct Backoff {
+    next: Duration,
+}
+impl Backoff {
+    const FIRST: Duration = Duration::from_millis(500);
+    const MAX: Duration = Duration::from_secs(30);
+    pub fn new() -> Self {
+        Self { next: Self::FIRST }
+    }
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(Self::MAX);
+        delay.mul_f64(0.5 + f64::from(OsRng.next_u32()) / f64::from(u32::MAX) / 2.0)
+    }
+}
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// The server's answer to a sent message
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    /// ID the server assigned to the message
+    pub id: u64,
+    /// How far the message got to each direct recipient, channels are not listed
+    pub statuses: BTreeMap<String, DeliveryStatus>,
+}
+impl SentMessage {
+    /// Recipients the message was not sent to because they have no account
+    pub fn rejected(&self) -> Vec<&str> {
+        self.statuses
+            .iter()
+            .filter(|(_, status)| **status == DeliveryStatus::UnknownUser)
+            .map(|(recipient, _)| recipient.as_str())
+            .collect()
+    }
+}
+/// Something the server pushed after [`Connection::subscribe`]
+#[derive(Debug, Clone)]
+pub enum Incoming {
+    Message(InboundMessage),
+    /// A direct message we sent was delivered to or read by one of its recipients
+    Receipt(Receipt),
+    /// A user we watch came online, went away or went offline
+    Presence(Presence),
+    /// The connection to the server dropped, see [`Connection::reconnect`]
+    Disconnected,
+    /// [`Connection::reconnect`] got us logged in and subscribed again
+    Reconnected,
+}
+/// Messages pushed by the server after [`Connection::subscribe`], usable from another thread
+pub struct MessageReceiver {
+    messages: Receiver<Pushed>,
+    generation: Arc<AtomicU64>,
+    secret_key: Arc<RwLock<Option<SecretKey>>>,
+}
+impl MessageReceiver {
+    /// Blocks until the server pushes something or the connection drops. Only fails for
+    /// good with [`RecvMessageError::Disconnected`] once the [`Connection`] is gone.
+    pub fn recv(&self) -> Result<Incoming, RecvMessageError> {
+        let (pushed, aes_key) = loop {
+            match self.messages.recv() {
+                Ok(Pushed::Packet(pushed, aes_key)) => break (pushed, aes_key),
+                Ok(Pushed::Event(incoming)) => return Ok(incoming),
+                Ok(Pushed::Disconnected(generation)) => {
+                    // Stale if we already reconnected since
+                    if generation == self.generation.load(Ordering::SeqCst) {
+                        return Ok(Incoming::Disconnected);
+                    }
+                }
+                Err(_) => return Err(RecvMessageError::Disconnected),
+            }
+        };
+        let incoming = match pushed {
+            SRecvMessage::NextMsg { message } => message.get(&aes_key).map(Incoming::Message),
+            SRecvMessage::Receipt { receipt } => receipt.get(&aes_key).map(Incoming::Receipt),
+            SRecvMessage::Presence { presence } => presence.get(&aes_key).map(Incoming::Presence),
+            _ => return Err(RecvMessageError::InvalidPacket),
+        };
+        match incoming {
+            Ok(incoming) => Ok(incoming),
+            Err(AesError::AuthenticationFailed) => Err(RecvMessageError::AuthenticationFailed),
+            Err(_) => Err(RecvMessageError::DeserializationError),
+        }
+    }
+    /// Blocks until the server pushes the next message, skipping everything else
+    pub fn recv_message(&self) -> Result<InboundMessage, RecvMessageError> {
+        loop {
+            if let Incoming::Message(message) = self.recv()? {
+                return Ok(message);
+            }
+        }
+    }
+    /// Returns the text of a received message, opening it if it was end-to-end encrypted
+    pub fn open(&self, contents: &MessageBody) -> Result<String, RecvMessageError> {
+        open_body(contents, self.secret_key.read().unwrap().as_ref())
+    }
+}
+fn open_body(
+    contents: &MessageBody,
+    secret_key: Option<&SecretKey>,
+) -> Result<String, RecvMessageError> {
+    let sealed = match contents {
+        MessageBody::Plain(contents) => return Ok(contents.clone()),
+        // The server only delivers our own box
+        MessageBody::Sealed(boxes) => boxes.values().next(),
+    };
+    let (Some(sealed), Some(secret_key)) = (sealed, secret_key) else {
+        return Err(RecvMessageError::NoSecretKey);
+    };
+    let contents = secret_key
+        .unseal(sealed)
+        .map_err(|_| RecvMessageError::AuthenticationFailed)?;
+    String::from_utf8(contents).map_err(|_| RecvMessageError::DeserializationError)
+}
+#[derive(Debug, Clone, Error)]
+pub enum ConnectError {
+    #[error("Could not reach server: {0}")]
+    Unreachable(String),
+    #[error("Handshake with the server failed: {0}")]
+    Handshake(HandshakeError),
+    #[error("No common protocol version, the server speaks versions {min} to {max} and we speak {} to {}", types::MIN_PROTOCOL_VERSION, types::PROTOCOL_VERSION)]
+    UnsupportedVersion { min: u32, max: u32 },
+    #[error("Server key {presented} does not match the pinned key {pinned}, remove the entry from the known hosts file or profile if the server key was changed on purpose")]
+    HostKeyMismatch { pinned: String, presented: String },
+    #[error("Failed to access known hosts file: {0}")]
+    KnownHosts(String),
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Reconnected, but failed to log back in: {0}")]
+    Relogin(LoginError),
+    #[error("Reconnected, but failed to restore the session: {0}")]
+    Resubscribe(String),
+}
+impl ConnectError {
+    /// Whether trying again later may succeed. A failed handshake is not, as it may mean the
+    /// connection is intercepted or the server turned us away for good, which the user has to
+    /// hear about.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ConnectError::Unreachable(_)
+                | ConnectError::Disconnected
+                | ConnectError::Resubscribe(_)
+                | ConnectError::Relogin(LoginError::Disconnected)
+        )
+    }
+}
+/// Why [`ConnectError::Handshake`] happened
+#[derive(Debug, Clone, Error)]
+pub enum HandshakeError {
+    #[error("Failed to generate a client key")]
+    KeyGeneration,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server answered with something other than a handshake")]
+    UnexpectedPacket,
+    #[error("Could not decrypt the session token")]
+    Token,
+    #[error("Could not decrypt the shared key")]
+    SharedKey,
+    #[error("Server did not sign the handshake with its key, the connection may be intercepted")]
+    Signature,
+}
+#[derive(Debug, Clone, Error)]
+pub enum SessionError {
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Invalid or expired session token")]
+    InvalidToken,
+    #[error("Failed to encrypt the request")]
+    EncryptionFailed,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum CreateAccountError {
+    #[error("Account already exists")]
+    AccountExists,
+    #[error("Username contains invalid characters")]
+    InvalidUsername,
+    #[error("Too many accounts were created from this address, try again later")]
+    TooManyAccounts,
+    #[error("Failed to store the account key: {0}")]
+    KeyStore(String),
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Failed to encrypt the request")]
+    EncryptionFailed,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum LoginError {
+    #[error("Incorrect password provided, or incorrect username")]
+    IncorrectPassword,
+    #[error("Failed to load the account key: {0}")]
+    KeyStore(String),
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Failed to encrypt the request")]
+    EncryptionFailed,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum AccountError {
+    #[error("Incorrect password")]
+    IncorrectPassword,
+    #[error("Username contains invalid characters")]
+    InvalidUsername,
+    #[error("Username is already taken")]
+    AccountExists,
+    #[error("Only admins can do that")]
+    NotAdmin,
+    #[error("That user has not asked to be renamed")]
+    NoRenameRequest,
+    #[error("No such session, or it is the current one")]
+    NoSuchSession,
+    #[error("Failed to store the account key: {0}")]
+    KeyStore(String),
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Failed to encrypt the request")]
+    EncryptionFailed,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum SendMessageError {
+    #[error("You are not a member of {0}")]
+    NotInChannel(String),
+    #[error("{0} has too many undelivered messages")]
+    QueueFull(String),
+    #[error("Encrypted messages can't be sent to channels such as {0}")]
+    SealedToChannel(String),
+    #[error("{0} has not published a key to encrypt messages to")]
+    NoPublicKey(String),
+    #[error("The key of {username} changed from {pinned} to {presented}, make sure it is theirs before trusting it")]
+    KeyChanged {
+        username: String,
+        pinned: String,
+        presented: String,
+    },
+    #[error("Failed to access known users file: {0}")]
+    KnownUsers(String),
+    #[error("Failed to encrypt the message")]
+    EncryptionFailed,
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum RecvMessageError {
+    #[error("Failed to parse AES data")]
+    DeserializationError,
+    #[error("Message failed authentication and may have been tampered with")]
+    AuthenticationFailed,
+    #[error("Message is end-to-end encrypted, but no key to open it is stored on this device, import it from another device")]
+    NoSecretKey,
+    #[error("Already subscribed to messages on this connection")]
+    AlreadySubscribed,
+    #[error("Failed to load the device ID: {0}")]
+    DeviceStore(String),
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Failed to encrypt the request")]
+    EncryptionFailed,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum ChannelError {
+    #[error("Channel names must start with '#' followed by letters, digits, '-' or '_'")]
+    InvalidChannel,
+    #[error("You are not a member of that channel")]
+    NotInChannel,
+    #[error("You are already a member of that channel")]
+    AlreadyInChannel,
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Failed to parse AES data")]
+    DeserializationError,
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Failed to encrypt the request")]
+    EncryptionFailed,
+    #[error("Disconnected from server")]
+    Disconnected,
+    #[error("Server error: {0}")]
+    Server(SError),
+    #[error("Server sent an invalid packet")]
+    InvalidPacket,
+}
+#[derive(Debug, Clone, Error)]
+pub enum KeyError {
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("That is not the key the account published")]
+    WrongKey,
+    #[error("{0} has not published a key")]
+    NoPublicKey(String),
+    #[error("Failed to access known users file: {0}")]
+    KnownUsers(String),
+    #[error("Failed to store the account key: {0}")]
+    KeyStore(String),
+    #[error("{0}")]
+    Session(#[from] SessionError),
+}
+/// Why a request got no reply, whatever it asked for
+#[derive(Debug)]
+enum RequestError {
+    Encryption,
+    Disconnected,
+}
+impl From<AesError> for RequestError {
+    fn from(_: AesError) -> Self {
+        RequestError::Encryption
+    }
+}
+impl From<RequestError> for SessionError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => SessionError::EncryptionFailed,
+            RequestError::Disconnected => SessionError::Disconnected,
+        }
+    }
+}
+impl From<RequestError> for CreateAccountError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => CreateAccountError::EncryptionFailed,
+            RequestError::Disconnected => CreateAccountError::Disconnected,
+        }
+    }
+}
+impl From<RequestError> for LoginError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => LoginError::EncryptionFailed,
+            RequestError::Disconnected => LoginError::Disconnected,
+        }
+    }
+}
+impl From<RequestError> for AccountError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => AccountError::EncryptionFailed,
+            RequestError::Disconnected => AccountError::Disconnected,
+        }
+    }
+}
+impl From<RequestError> for SendMessageError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => SendMessageError::EncryptionFailed,
+            RequestError::Disconnected => SendMessageError::Disconnected,
+        }
+    }
+}
+impl From<RequestError> for RecvMessageError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => RecvMessageError::EncryptionFailed,
+            RequestError::Disconnected => RecvMessageError::Disconnected,
+        }
+    }
+}
+impl From<RequestError> for ChannelError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Encryption => ChannelError::EncryptionFailed,
+            RequestError::Disconnected => ChannelError::Disconnected,
+        }
+    }
+}
+impl From<SessionError> for LoginError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::InvalidToken => LoginError::InvalidToken,
+            SessionError::Disconnected => LoginError::Disconnected,
+            SessionError::Server(e) => LoginError::Server(e),
+            SessionError::EncryptionFailed => LoginError::EncryptionFailed,
+            SessionError::NotLoggedIn | SessionError::InvalidPacket => LoginError::InvalidPacket,
+        }
+    }
+}
+impl From<SessionError> for SendMessageError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::NotLoggedIn => SendMessageError::NotLoggedIn,
+            SessionError::InvalidToken => SendMessageError::InvalidToken,
+            SessionError::Disconnected => SendMessageError::Disconnected,
+            SessionError::Server(e) => SendMessageError::Server(e),
+            SessionError::EncryptionFailed => SendMessageError::EncryptionFailed,
+            SessionError::InvalidPacket => SendMessageError::InvalidPacket,
+        }
+    }
+}
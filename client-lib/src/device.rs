@@ -0,0 +1,52 @@
+use std::{
+    convert::TryInto,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use rsa::rand_core::{OsRng, RngCore};
+use types::Device;
+
+/// Where the ID of this device is kept, shared by every account and server so the server can
+/// tell this device's sessions apart from the user's other devices
+pub fn default_path() -> PathBuf {
+    match dirs::config_dir() {
+        Some(dir) => dir.join("irc").join("device"),
+        None => PathBuf::from("device"),
+    }
+}
+/// Loads the device ID stored at `path`, generating and saving one on first use. The device is
+/// named after the host so the user can recognise it when listing their sessions.
+pub fn load_or_create(path: &Path) -> io::Result<Device> {
+    let id = match fs::read(path) {
+        Ok(bytes) => u128::from_le_bytes(
+            bytes
+                .try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Corrupt device file"))?,
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut bytes = [0; 16];
+            OsRng.fill_bytes(&mut bytes);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::File::create(path)?.write_all(&bytes)?;
+            u128::from_le_bytes(bytes)
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(Device {
+        id,
+        name: hostname(),
+    })
+}
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown device".to_string())
+}
@@ -0,0 +1,67 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// Key fingerprints pinned the first time they are seen, stored as `<name> <fingerprint>` lines.
+/// Server keys are pinned by address, and other users' keys by `<user>@<address>` in a file of
+/// their own.
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, String>,
+}
+pub enum HostKeyStatus {
+    Trusted,
+    Unknown,
+    Mismatch { pinned: String },
+}
+impl KnownHosts {
+    pub fn default_path() -> PathBuf {
+        match dirs::config_dir() {
+            Some(dir) => dir.join("irc").join("known_hosts"),
+            None => PathBuf::from("known_hosts"),
+        }
+    }
+    /// Where other users' keys are pinned
+    pub fn known_users_path() -> PathBuf {
+        match dirs::config_dir() {
+            Some(dir) => dir.join("irc").join("known_users"),
+            None => PathBuf::from("known_users"),
+        }
+    }
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let hosts = contents
+            .lines()
+            .filter_map(|line| line.trim().split_once(' '))
+            .map(|(addr, fingerprint)| (addr.to_string(), fingerprint.trim().to_string()))
+            .collect();
+        Ok(Self { path, hosts })
+    }
+    pub fn check(&self, addr: &str, fingerprint: &str) -> HostKeyStatus {
+        match self.hosts.get(addr) {
+            Some(pinned) if pinned == fingerprint => HostKeyStatus::Trusted,
+            Some(pinned) => HostKeyStatus::Mismatch {
+                pinned: pinned.clone(),
+            },
+            None => HostKeyStatus::Unknown,
+        }
+    }
+    pub fn pin(&mut self, addr: &str, fingerprint: &str) -> io::Result<()> {
+        self.hosts.insert(addr.to_string(), fingerprint.to_string());
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::File::create(&self.path)?;
+        for (addr, fingerprint) in &self.hosts {
+            writeln!(file, "{addr} {fingerprint}")?;
+        }
+        Ok(())
+    }
+}
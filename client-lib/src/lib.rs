@@ -0,0 +1,51 @@
+//! Talks to the IRC server without any UI, for the TUI client as well as bots and other
+//! integrations.
+//!
+//! [`Connection::new`] connects and handshakes, pinning the server key in the known hosts file
+//! the first time a server is seen. Once logged in with [`Connection::login`] messages are sent
+//! with [`Connection::send_message`] or [`Connection::send_encrypted_message`], and
+//! [`Connection::subscribe`] returns a [`MessageReceiver`] the server pushes messages, receipts
+//! and presence changes to. The receiver can be moved to another thread while requests keep
+//! going through the connection. The session token is swapped for a fresh one regularly, and a
+//! session the server dropped or let expire is logged back into with the same password.
+//!
+//! ```no_run
+//! use client_lib::{Connection, Incoming};
+//!
+//! let mut conn = Connection::new("localhost:65432")?;
+//! conn.login("echo-bot".to_string(), "hunter2")?;
+//! let receiver = conn.subscribe()?;
+//! while let Ok(incoming) = receiver.recv() {
+//!     match incoming {
+//!         Incoming::Message(msg) => {
+//!             let text = receiver.open(&msg.contents)?;
+//!             conn.send_encrypted_message(vec![msg.sender], text)?;
+//!         }
+//!         Incoming::Disconnected => conn.reconnect_with_backoff()?,
+//!         _ => {}
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Account keys for end-to-end encryption, known hosts and the device ID are kept in the `irc`
+//! directory of the user's config directory, or wherever [`Connection::with_paths`] is told
+//! to keep them. So are the keys of other users, pinned the first
+//! time a message is sealed to them: sealing fails with [`SendMessageError::KeyChanged`] once
+//! a user's key changes, until [`Connection::trust_key`] accepts the new one.
+
+mod connection;
+mod device;
+mod known_hosts;
+mod paths;
+mod user_keys;
+
+pub use connection::{
+    AccountError, Backoff, ChannelError, ConnectError, Connection, CreateAccountError,
+    HandshakeError, Incoming, KeyError, LoginError, MessageReceiver, RecvMessageError,
+    SendMessageError, SentMessage, SessionError,
+};
+pub use paths::Paths;
+/// The protocol types messages, presence and sessions are described with, so users of this
+/// crate don't need to depend on it themselves
+pub use types;
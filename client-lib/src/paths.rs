@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use crate::{device, known_hosts::KnownHosts, user_keys::UserKeys};
+
+/// Where a [`Connection`](crate::Connection) keeps what it stores on disk, by default in the
+/// `irc` directory of the user's config directory. Bots running several accounts, or next to
+/// the TUI, point these somewhere of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    /// Server keys pinned by address
+    pub known_hosts: PathBuf,
+    /// Other users' keys pinned by `<user>@<address>`
+    pub known_users: PathBuf,
+    /// Directory of our account keys, one file per account and server
+    pub user_keys: PathBuf,
+    /// The ID this device subscribes as
+    pub device: PathBuf,
+}
+impl Paths {
+    /// Every file in `dir`, named as in the default config directory
+    pub fn in_dir(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self {
+            known_hosts: dir.join("known_hosts"),
+            known_users: dir.join("known_users"),
+            user_keys: dir.join("keys"),
+            device: dir.join("device"),
+        }
+    }
+}
+impl Default for Paths {
+    fn default() -> Self {
+        Self {
+            known_hosts: KnownHosts::default_path(),
+            known_users: KnownHosts::known_users_path(),
+            user_keys: UserKeys::default_dir(),
+            device: device::default_path(),
+        }
+    }
+}
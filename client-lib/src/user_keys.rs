@@ -0,0 +1,66 @@
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use crypto_box::{aead::OsRng, SecretKey};
+
+/// Long-term secret keys used to open end-to-end encrypted messages, one file per account and
+/// server holding the 32 raw key bytes
+pub struct UserKeys {
+    dir: PathBuf,
+}
+impl UserKeys {
+    pub fn default_dir() -> PathBuf {
+        match dirs::config_dir() {
+            Some(dir) => dir.join("irc").join("keys"),
+            None => PathBuf::from("keys"),
+        }
+    }
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+    fn path(&self, addr: &str, username: &str) -> PathBuf {
+        let name: String = format!("{username}@{addr}")
+            .chars()
+            .map(|chr| {
+                if chr.is_alphanumeric() || "@.-_".contains(chr) {
+                    chr
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir.join(name)
+    }
+    pub fn load(&self, addr: &str, username: &str) -> io::Result<Option<SecretKey>> {
+        let bytes = match fs::read(self.path(addr, username)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Corrupt user key file"))?;
+        Ok(Some(SecretKey::from(bytes)))
+    }
+    /// Generates a new key and saves it, replacing any key already stored for the account
+    pub fn generate(&self, addr: &str, username: &str) -> io::Result<SecretKey> {
+        let key = SecretKey::generate(&mut OsRng);
+        self.save(addr, username, &key)?;
+        Ok(key)
+    }
+    /// Stores `key` for the account, replacing any key already stored for it
+    pub fn save(&self, addr: &str, username: &str, key: &SecretKey) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(self.path(addr, username))?
+            .write_all(&key.to_bytes())
+    }
+}
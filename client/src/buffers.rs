@@ -0,0 +1,286 @@
+use types::InboundMessage;
+
+/// Name of the buffer holding notices that belong to no conversation
+pub const STATUS: &str = "*status*";
+/// Rows kept per buffer, the oldest are dropped beyond this
+const MAX_ROWS: usize = 1000;
+
+/// A line in a buffer
+#[derive(Debug, Clone)]
+pub struct Row {
+    /// ID of the message shown, if the row shows one
+    pub id: Option<u64>,
+    pub label: String,
+    pub contents: String,
+}
+impl Row {
+    /// A row about no message in particular, e.g. command output
+    pub fn notice(label: String, contents: String) -> Self {
+        Self {
+            id: None,
+            label,
+            contents,
+        }
+    }
+}
+
+/// A conversation with a user, a group of users or a channel
+pub struct Buffer {
+    /// Where messages typed into the buffer go, a channel or users separated by commas
+    pub name: String,
+    pub rows: Vec<Row>,
+    /// Rows added since the buffer was last shown
+    pub unread: usize,
+    /// Direct messages whose sender hasn't been told we read them
+    unread_ids: Vec<u64>,
+    /// Whether the scrollback was fetched from the server yet
+    pub history_loaded: bool,
+}
+impl Buffer {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            rows: Vec::new(),
+            unread: 0,
+            unread_ids: Vec::new(),
+            history_loaded: name == STATUS,
+        }
+    }
+}
+
+/// The open buffers in the order they were opened, starting with the status buffer
+pub struct Buffers {
+    buffers: Vec<Buffer>,
+    active: usize,
+}
+impl Buffers {
+    pub fn new() -> Self {
+        Self {
+            buffers: vec![Buffer::new(STATUS)],
+            active: 0,
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Buffer> {
+        self.buffers.iter()
+    }
+    pub fn active(&self) -> &Buffer {
+        &self.buffers[self.active]
+    }
+    pub fn active_index(&self) -> usize {
+        self.active
+    }
+    /// Index of the buffer `name`, opening it after the others if it isn't open yet
+    pub fn open(&mut self, name: &str) -> usize {
+        match self.find(name) {
+            Some(index) => index,
+            None => {
+                self.buffers.push(Buffer::new(name));
+                self.buffers.len() - 1
+            }
+        }
+    }
+    /// Makes buffer `index` the active one and clears its unread counter. Returns the direct
+    /// messages to mark read now that they are shown, or `None` if there is no such buffer.
+    pub fn switch(&mut self, index: usize) -> Option<Vec<u64>> {
+        let buffer = self.buffers.get_mut(index)?;
+        self.active = index;
+        buffer.unread = 0;
+        Some(std::mem::take(&mut buffer.unread_ids))
+    }
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.buffers.iter().position(|buffer| buffer.name == name)
+    }
+    /// Index of the buffer `by` places after the active one, wrapping around
+    pub fn offset(&self, by: isize) -> usize {
+        let len = self.buffers.len() as isize;
+        (self.active as isize + by).rem_euclid(len) as usize
+    }
+    /// Closes buffer `index`, activating the one before it if it was active. The status buffer
+    /// can't be closed.
+    pub fn close(&mut self, index: usize) -> bool {
+        if index == 0 || index >= self.buffers.len() {
+            return false;
+        }
+        self.buffers.remove(index);
+        if self.active >= index {
+            self.active -= 1;
+        }
+        true
+    }
+    /// Adds `row` to the buffer `name`, opening it if needed. Returns whether the row is
+    /// shown, otherwise it counts as unread.
+    pub fn push(&mut self, name: &str, row: Row) -> bool {
+        let index = self.open(name);
+        let buffer = &mut self.buffers[index];
+        buffer.rows.push(row);
+        if buffer.rows.len() > MAX_ROWS {
+            buffer.rows.remove(0);
+        }
+        if index == self.active {
+            return true;
+        }
+        buffer.unread += 1;
+        false
+    }
+    /// Holds back the read receipt for message `id` until the buffer `name` is shown
+    pub fn defer_read(&mut self, name: &str, id: u64) {
+        let index = self.open(name);
+        self.buffers[index].unread_ids.push(id);
+    }
+    /// Puts the scrollback fetched for buffer `name` before the rows it already has, leaving
+    /// out rows for messages the scrollback includes
+    pub fn load_history(&mut self, name: &str, mut rows: Vec<Row>) {
+        let index = self.open(name);
+        let buffer = &mut self.buffers[index];
+        let mut newer: Vec<Row> = std::mem::take(&mut buffer.rows)
+            .into_iter()
+            .filter(|row| {
+                row.id
+                    .is_none_or(|id| !rows.iter().any(|fetched| fetched.id == Some(id)))
+            })
+            .collect();
+        rows.append(&mut newer);
+        buffer.rows = rows;
+        buffer.history_loaded = true;
+    }
+    /// Name of the buffer showing message `id`
+    pub fn find_message(&self, id: u64) -> Option<&str> {
+        self.buffers
+            .iter()
+            .find(|buffer| buffer.rows.iter().any(|row| row.id == Some(id)))
+            .map(|buffer| buffer.name.as_str())
+    }
+}
+impl Default for Buffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The buffer a message belongs in: its channel, or everyone in the conversation but us
+pub fn conversation(msg: &InboundMessage, me: &str) -> String {
+    match &msg.channel {
+        Some(channel) => channel.clone(),
+        None => name(
+            msg.recipients
+                .iter()
+                .chain(std::iter::once(&msg.sender))
+                .filter(|user| *user != me)
+                .cloned()
+                .collect(),
+            me,
+        ),
+    }
+}
+/// The buffer for messages to `recipients`, the same whatever order they are listed in
+pub fn name(mut recipients: Vec<String>, me: &str) -> String {
+    recipients.retain(|recipient| recipient != me);
+    recipients.sort();
+    recipients.dedup();
+    if recipients.is_empty() {
+        // Notes to self
+        return me.to_string();
+    }
+    recipients.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::MessageBody;
+
+    fn row(id: u64) -> Row {
+        Row {
+            id: Some(id),
+            label: "alice".to_string(),
+            contents: id.to_string(),
+        }
+    }
+    fn ids(buffer: &Buffer) -> Vec<Option<u64>> {
+        buffer.rows.iter().map(|row| row.id).collect()
+    }
+    fn message(sender: &str, recipients: &[&str], channel: Option<&str>) -> InboundMessage {
+        InboundMessage {
+            id: 0,
+            timestamp: 0,
+            sender: sender.to_string(),
+            recipients: recipients.iter().map(|user| user.to_string()).collect(),
+            contents: MessageBody::Plain(String::new()),
+            channel: channel.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn rows_count_as_unread_until_shown() {
+        let mut buffers = Buffers::new();
+        assert!(!buffers.push("bob", row(1)));
+        buffers.defer_read("bob", 1);
+        assert!(!buffers.push("bob", row(2)));
+        let bob = buffers.find("bob").unwrap();
+        assert_eq!(buffers.iter().nth(bob).unwrap().unread, 2);
+        assert_eq!(buffers.switch(bob), Some(vec![1]));
+        assert_eq!(buffers.active().unread, 0);
+        assert!(buffers.push("bob", row(3)));
+        assert_eq!(buffers.active().unread, 0);
+        assert_eq!(buffers.switch(bob), Some(Vec::new()));
+        assert_eq!(buffers.switch(5), None);
+    }
+
+    #[test]
+    fn closing_keeps_the_status_buffer_and_moves_the_active_one() {
+        let mut buffers = Buffers::new();
+        buffers.open("alice");
+        let bob = buffers.open("bob");
+        buffers.open("#rust");
+        buffers.switch(bob);
+        assert!(!buffers.close(0));
+        assert!(!buffers.close(4));
+        assert!(buffers.close(bob));
+        assert_eq!(buffers.active().name, "alice");
+        // Closing one before the active buffer keeps the same buffer active
+        buffers.switch(buffers.find("#rust").unwrap());
+        assert!(buffers.close(1));
+        assert_eq!(buffers.active().name, "#rust");
+        assert_eq!(buffers.offset(1), 0);
+        assert_eq!(buffers.offset(-1), 0);
+    }
+
+    #[test]
+    fn history_goes_before_newer_rows_without_repeating_them() {
+        let mut buffers = Buffers::new();
+        buffers.push("bob", row(3));
+        buffers.push("bob", row(4));
+        buffers.push("bob", Row::notice("*".to_string(), "notice".to_string()));
+        buffers.load_history("bob", vec![row(1), row(2), row(3)]);
+        let bob = buffers.iter().find(|buffer| buffer.name == "bob").unwrap();
+        assert_eq!(ids(bob), [Some(1), Some(2), Some(3), Some(4), None]);
+        assert!(bob.history_loaded);
+        assert_eq!(buffers.find_message(4), Some("bob"));
+        assert_eq!(buffers.find_message(5), None);
+    }
+
+    #[test]
+    fn conversations_are_named_after_everyone_else() {
+        assert_eq!(
+            conversation(&message("alice", &["bob"], None), "bob"),
+            "alice"
+        );
+        assert_eq!(
+            conversation(&message("bob", &["alice"], None), "bob"),
+            "alice"
+        );
+        assert_eq!(
+            conversation(&message("carol", &["bob", "alice"], None), "bob"),
+            "alice,carol"
+        );
+        assert_eq!(
+            conversation(&message("alice", &["#rust"], Some("#rust")), "bob"),
+            "#rust"
+        );
+        assert_eq!(conversation(&message("bob", &["bob"], None), "bob"), "bob");
+        assert_eq!(
+            name(vec!["carol".into(), "alice".into(), "carol".into()], "bob"),
+            "alice,carol"
+        );
+    }
+}
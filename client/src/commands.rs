@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+/// A line typed into the message box
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input<'a> {
+    /// Text to send, a leading `//` stands for a literal `/`
+    Message(&'a str),
+    /// `/name args`, with the arguments left unsplit
+    Command { name: &'a str, args: &'a str },
+}
+pub fn parse(line: &str) -> Input<'_> {
+    let line = line.trim();
+    match line.strip_prefix('/') {
+        Some(rest) if rest.starts_with('/') => Input::Message(rest),
+        Some(rest) => {
+            let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            Input::Command {
+                name,
+                args: args.trim(),
+            }
+        }
+        None => Input::Message(line),
+    }
+}
+
+/// Runs a command against the UI state `C`, failing with a message for the user
+pub type Handler<C> = fn(&mut C, Vec<String>) -> Result<(), String>;
+
+pub struct Command<C> {
+    pub name: &'static str,
+    /// Arguments as shown by /help, e.g. `<user> <message>`
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    /// Whether the last argument takes the rest of the line, spaces included
+    pub rest: bool,
+    pub run: Handler<C>,
+}
+impl<C> Command<C> {
+    /// Splits `args` into words, or `None` if there are too few or too many
+    fn split(&self, args: &str) -> Option<Vec<String>> {
+        let mut words = Vec::new();
+        let mut remaining = args.trim();
+        while !remaining.is_empty() {
+            if self.rest && words.len() + 1 == self.max_args {
+                words.push(remaining.to_string());
+                break;
+            }
+            let (word, rest) = remaining
+                .split_once(char::is_whitespace)
+                .unwrap_or((remaining, ""));
+            words.push(word.to_string());
+            remaining = rest.trim_start();
+        }
+        (self.min_args..=self.max_args)
+            .contains(&words.len())
+            .then_some(words)
+    }
+}
+
+/// Commands by name, new ones are added with [`Registry::register`]
+pub struct Registry<C> {
+    commands: BTreeMap<&'static str, Command<C>>,
+}
+impl<C> Registry<C> {
+    pub fn new() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+    /// Adds `command`, replacing any command of the same name
+    pub fn register(&mut self, command: Command<C>) {
+        self.commands.insert(command.name, command);
+    }
+    pub fn get(&self, name: &str) -> Option<&Command<C>> {
+        self.commands.get(name.trim_start_matches('/'))
+    }
+    /// Every command, sorted by name
+    pub fn commands(&self) -> impl Iterator<Item = &Command<C>> {
+        self.commands.values()
+    }
+    pub fn run(&self, ctx: &mut C, name: &str, args: &str) -> Result<(), CommandError> {
+        let Some(command) = self.commands.get(name) else {
+            return Err(CommandError::Unknown(name.to_string()));
+        };
+        let Some(args) = command.split(args) else {
+            return Err(CommandError::Usage {
+                name: command.name,
+                usage: command.usage,
+            });
+        };
+        (command.run)(ctx, args).map_err(CommandError::Failed)
+    }
+}
+impl<C> Default for Registry<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a comma separated list of users and channels, rejecting names no account or channel
+/// could have
+pub fn parse_recipients(list: &str) -> Result<Vec<String>, String> {
+    let recipients: Vec<String> = list
+        .split(',')
+        .map(str::trim)
+        .filter(|recipient| !recipient.is_empty())
+        .map(str::to_string)
+        .collect();
+    if recipients.is_empty() {
+        return Err("No recipients given, open a conversation with /query or use /msg".to_string());
+    }
+    for recipient in &recipients {
+        if recipient.starts_with('#') {
+            if !types::is_channel_name(recipient) {
+                return Err(format!(
+                    "{recipient} is not a valid channel name, channel names are '#' followed by \
+                     letters, digits, '-' or '_'"
+                ));
+            }
+        } else if recipient.chars().any(char::is_whitespace) {
+            return Err(format!("{recipient:?} is not a valid username"));
+        }
+    }
+    Ok(recipients)
+}
+/// Reads a key as shown by `/key export`, 64 hex digits
+pub fn parse_key(hex: &str) -> Result<[u8; 32], String> {
+    let invalid = || "Keys are 64 hex digits, as shown by /key export".to_string();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum CommandError {
+    #[error("Unknown command /{0}, try /help")]
+    Unknown(String),
+    #[error("Usage: /{name} {usage}")]
+    Usage {
+        name: &'static str,
+        usage: &'static str,
+    },
+    #[error("{0}")]
+    Failed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(min_args: usize, max_args: usize, rest: bool) -> Command<Vec<String>> {
+        Command {
+            name: "test",
+            usage: "<args>",
+            help: "",
+            min_args,
+            max_args,
+            rest,
+            run: |ctx, args| {
+                *ctx = args;
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn parses_commands_and_messages() {
+        assert_eq!(parse("  hello  "), Input::Message("hello"));
+        assert_eq!(parse("//me waves"), Input::Message("/me waves"));
+        assert_eq!(
+            parse("/msg bob  hello  there "),
+            Input::Command {
+                name: "msg",
+                args: "bob  hello  there"
+            }
+        );
+        assert_eq!(
+            parse("/quit"),
+            Input::Command {
+                name: "quit",
+                args: ""
+            }
+        );
+    }
+
+    #[test]
+    fn splits_words() {
+        let command = command(1, 2, false);
+        assert_eq!(command.split(" a   b "), Some(vec!["a".into(), "b".into()]));
+        assert_eq!(command.split(""), None);
+        assert_eq!(command.split("a b c"), None);
+    }
+
+    #[test]
+    fn last_argument_takes_the_rest_of_the_line() {
+        let command = command(2, 2, true);
+        assert_eq!(
+            command.split("bob  hello  there "),
+            Some(vec!["bob".into(), "hello  there".into()])
+        );
+        assert_eq!(command.split("bob"), None);
+    }
+
+    #[test]
+    fn runs_registered_commands() {
+        let mut registry = Registry::new();
+        registry.register(command(0, 1, false));
+        let mut ran = Vec::new();
+        registry.run(&mut ran, "test", "arg").unwrap();
+        assert_eq!(ran, vec!["arg".to_string()]);
+        assert!(registry.get("/test").is_some());
+        assert!(matches!(
+            registry.run(&mut ran, "nope", ""),
+            Err(CommandError::Unknown(name)) if name == "nope"
+        ));
+        assert!(matches!(
+            registry.run(&mut ran, "test", "a b"),
+            Err(CommandError::Usage { name: "test", .. })
+        ));
+    }
+
+    #[test]
+    fn parses_recipients() {
+        assert_eq!(
+            parse_recipients(" alice, #general ,,bob"),
+            Ok(vec!["alice".into(), "#general".into(), "bob".into()])
+        );
+        assert!(parse_recipients(" , ").is_err());
+        assert!(parse_recipients("alice,#no good").is_err());
+        assert!(parse_recipients("#").is_err());
+    }
+
+    #[test]
+    fn parses_exported_keys() {
+        let hex: String = (0..32).map(|byte| format!("{byte:02x}")).collect();
+        let key = parse_key(&hex).unwrap();
+        assert_eq!(key[0], 0);
+        assert_eq!(key[31], 31);
+        assert!(parse_key(&hex[2..]).is_err());
+        assert!(parse_key(&hex.replace('0', "g")).is_err());
+        assert!(parse_key(&format!("é{}", &hex[2..])).is_err());
+    }
+}
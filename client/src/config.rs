@@ -0,0 +1,148 @@
+use anyhow::{bail, Context};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Parser, Debug)]
+#[command(about = "IRC client")]
+pub struct Args {
+    /// Path to the client config file, defaults to client.toml in the user config directory
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address of the server to connect to, skipping the connect dialog
+    #[arg(short, long)]
+    pub server: Option<String>,
+    /// Name of the profile in the config file to connect with
+    #[arg(short, long, conflicts_with = "server")]
+    pub profile: Option<String>,
+    /// Username to fill in at login
+    #[arg(short, long)]
+    pub username: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Profile connected to on startup when none is given on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A server the user connects to by name
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub address: String,
+    /// Filled in at login, updated whenever the user logs in as someone else
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Fingerprint the server key must have, checked instead of the known hosts file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_key: Option<String>,
+}
+
+/// Where to connect, resolved from the command line, a profile or the connect dialog
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// Profile the target came from, which remembers the username logged in with
+    pub profile: Option<String>,
+    pub address: String,
+    pub username: Option<String>,
+    pub server_key: Option<String>,
+}
+impl Target {
+    pub fn from_profile(name: &str, profile: &Profile) -> Self {
+        Self {
+            profile: Some(name.to_string()),
+            address: profile.address.clone(),
+            username: profile.username.clone(),
+            server_key: profile.server_key.clone(),
+        }
+    }
+    pub fn from_address(address: &str) -> Self {
+        Self {
+            profile: None,
+            address: address.to_string(),
+            username: None,
+            server_key: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        match dirs::config_dir() {
+            Some(dir) => dir.join("irc").join("client.toml"),
+            None => PathBuf::from("client.toml"),
+        }
+    }
+    /// Loads the config at `path`, a missing file is the same as an empty one
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))
+            }
+        };
+        let config: Self = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Rewrites the file at `path`, dropping any comments in it
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, toml::to_string(self)?)
+            .with_context(|| format!("Failed to write config file {}", path.display()))
+    }
+    /// Where to connect on startup, `None` leaves it to the connect dialog
+    pub fn target(&self, args: &Args) -> anyhow::Result<Option<Target>> {
+        let mut target = match (&args.server, &args.profile) {
+            (Some(address), _) => Target::from_address(address),
+            (None, Some(name)) => match self.profiles.get(name) {
+                Some(profile) => Target::from_profile(name, profile),
+                None => bail!("No profile named {name:?} in the config file"),
+            },
+            (None, None) => match &self.default_profile {
+                Some(name) => Target::from_profile(name, &self.profiles[name]),
+                None => return Ok(None),
+            },
+        };
+        if args.username.is_some() {
+            target.username = args.username.clone();
+        }
+        Ok(Some(target))
+    }
+    /// Remembers `username` in the profile `name`, if there is one
+    pub fn remember_username(&mut self, name: &str, username: &str) -> bool {
+        match self.profiles.get_mut(name) {
+            Some(profile) if profile.username.as_deref() != Some(username) => {
+                profile.username = Some(username.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(name) = &self.default_profile {
+            if !self.profiles.contains_key(name) {
+                bail!("default_profile names the missing profile {name:?}");
+            }
+        }
+        for (name, profile) in &self.profiles {
+            if profile.address.is_empty() {
+                bail!("Profile {name:?} has no address");
+            }
+        }
+        Ok(())
+    }
+}
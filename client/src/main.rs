@@ -1,97 +1,216 @@
-use connection::Connection;
+use buffers::{Buffers, Row};
+use chrono::{Local, LocalResult, TimeZone};
+use clap::Parser;
+use client_lib::{Backoff, Connection, Incoming, RecvMessageError};
+use commands::{Command, Input, Registry};
+use config::{Args, Config, Target};
 use cursive::{
-    event::Event,
+    event::{Event, Key},
     theme::Palette,
-    view::Nameable,
-    views::{self, Button, EditView, LinearLayout, ListView, ResizedView, TextView},
-    Cursive,
+    view::{Nameable, View},
+    views::{
+        self, Button, Dialog, DummyView, EditView, LinearLayout, ListView, ResizedView, SelectView,
+        TextView,
+    },
+    CbSink, Cursive,
 };
+use std::{collections::BTreeMap, path::PathBuf, sync::mpsc};
+use types::{DeliveryStatus, InboundMessage, MessageBody, Presence, PresenceStatus, Receipt};
 
-mod connection;
+mod buffers;
+mod commands;
+mod config;
 
 fn main() {
+    let args = Args::parse();
+    let config_path = args.config.clone().unwrap_or_else(Config::default_path);
+    let loaded = Config::load(&config_path).and_then(|config| {
+        let target = config.target(&args)?;
+        Ok((config, target))
+    });
+    let (config, target) = loaded.unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(1)
+    });
+
     let mut c = cursive::default();
-    c.add_global_callback(Event::Key(cursive::event::Key::Esc), |s| s.quit());
+    c.add_global_callback(Event::Key(Key::Esc), |s| s.quit());
+    c.add_global_callback(Event::CtrlChar('n'), |s| switch_by(s, 1));
+    c.add_global_callback(Event::CtrlChar('p'), |s| switch_by(s, -1));
+    for index in 0..9 {
+        let digit = char::from_digit(index as u32 + 1, 10).unwrap();
+        c.add_global_callback(Event::AltChar(digit), move |s| switch_buffer(s, index));
+    }
     c.set_theme(cursive::theme::Theme {
         shadow: true,
         borders: cursive::theme::BorderStyle::Simple,
         palette: Palette::terminal_default(),
     });
 
-    let conn = Connection::new("zoe.soutter.com:65432").unwrap();
-    let conn2 = Connection::new("zoe.soutter.com:65432").unwrap();
     c.set_user_data(AppState {
-        main_connection: Some(conn),
-        secondary_connection: Some(conn2),
-        ..Default::default()
+        config,
+        config_path,
+        ..AppState::default()
     });
 
     let main_app = cursive::views::Dialog::around(
         LinearLayout::vertical()
-            .child(ListView::new().with_name("message_list"))
             .child(
-                LinearLayout::vertical()
-                    .child(
-                        LinearLayout::horizontal()
-                            .child(ResizedView::new(
-                                cursive::view::SizeConstraint::Fixed(13),
-                                cursive::view::SizeConstraint::Fixed(1),
-                                TextView::new("Destination: "),
-                            ))
-                            .child(ResizedView::new(
-                                cursive::view::SizeConstraint::AtLeast(20),
-                                cursive::view::SizeConstraint::Fixed(1),
-                                EditView::new()
-                                    .on_submit(|s, text| {
-                                        s.focus_name("msg_box").unwrap();
-                                    })
-                                    .with_name("dest_box"),
-                            )),
+                LinearLayout::horizontal()
+                    .child(ResizedView::new(
+                        cursive::view::SizeConstraint::Fixed(20),
+                        cursive::view::SizeConstraint::Free,
+                        SelectView::<usize>::new()
+                            .on_submit(|s, index: &usize| {
+                                switch_buffer(s, *index);
+                                let _ = s.focus_name("msg_box");
+                            })
+                            .with_name("buffer_list"),
+                    ))
+                    .child(ResizedView::new(
+                        cursive::view::SizeConstraint::Full,
+                        cursive::view::SizeConstraint::Free,
+                        ListView::new().with_name("message_list"),
+                    ))
+                    .child(ResizedView::new(
+                        cursive::view::SizeConstraint::Fixed(24),
+                        cursive::view::SizeConstraint::Free,
+                        ListView::new().with_name("user_list"),
+                    )),
+            )
+            .child(
+                LinearLayout::horizontal()
+                    .child(ResizedView::new(
+                        cursive::view::SizeConstraint::Fixed(13),
+                        cursive::view::SizeConstraint::Fixed(1),
+                        TextView::new("Message: "),
+                    ))
+                    .child(ResizedView::new(
+                        cursive::view::SizeConstraint::AtLeast(20),
+                        cursive::view::SizeConstraint::Fixed(1),
+                        EditView::new().on_submit(submit).with_name("msg_box"),
+                    )),
+            )
+            .child(TextView::new("Not connected").with_name("status")),
+    );
+    c.add_layer(main_app);
+    show_buffer(&mut c);
+    match target {
+        Some(target) => connect(&mut c, target),
+        None => connect_dialog(&mut c),
+    }
+
+    c.run();
+}
+#[derive(Default)]
+struct AppState {
+    main_connection: Option<Connection>,
+    /// Where `main_connection` is connected to
+    target: Option<Target>,
+    config: Config,
+    config_path: PathBuf,
+    /// Users shown in the user list, with their last known presence
+    contacts: BTreeMap<String, PresenceStatus>,
+    /// Conversations listed in the sidebar, the active one is shown in the message list
+    buffers: Buffers,
+}
+/// Lets the user pick a profile from the config file or type in a server address
+fn connect_dialog(s: &mut Cursive) {
+    let profiles: Vec<(String, Target)> = s
+        .with_user_data(|dat: &mut AppState| {
+            dat.config
+                .profiles
+                .iter()
+                .map(|(name, profile)| {
+                    (
+                        format!("{name} ({})", profile.address),
+                        Target::from_profile(name, profile),
                     )
-                    .child(
-                        LinearLayout::horizontal()
-                            .child(ResizedView::new(
-                                cursive::view::SizeConstraint::Fixed(13),
-                                cursive::view::SizeConstraint::Fixed(1),
-                                TextView::new("Message: "),
-                            ))
-                            .child(ResizedView::new(
-                                cursive::view::SizeConstraint::AtLeast(20),
-                                cursive::view::SizeConstraint::Fixed(1),
-                                EditView::new()
-                                    .on_submit(|s, text| {
-                                        let recipients = s
-                                            .find_name::<EditView>("dest_box")
-                                            .unwrap()
-                                            .get_content()
-                                            .clone();
-                                        s.with_user_data(|dat: &mut AppState| {
-                                            let recipients: Vec<_> = recipients
-                                                .split(",")
-                                                .map(|usr| {
-                                                    let x = usr
-                                                        .trim()
-                                                        .chars()
-                                                        .filter(|chr| chr.is_alphanumeric())
-                                                        .collect();
-                                                    x
-                                                })
-                                                .collect();
-                                            dat.main_connection
-                                                .as_mut()
-                                                .unwrap()
-                                                .send_message(recipients, text.to_string())
-                                                .unwrap();
-                                        })
-                                        .unwrap();
-                                        s.find_name::<EditView>("msg_box").unwrap().set_content("");
-                                    })
-                                    .with_name("msg_box"),
-                            )),
-                    ),
-            ),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut layout = LinearLayout::vertical();
+    if !profiles.is_empty() {
+        layout.add_child(TextView::new("Profiles"));
+        layout.add_child(
+            SelectView::new()
+                .with_all(profiles)
+                .on_submit(|s, target: &Target| {
+                    s.pop_layer();
+                    connect(s, target.clone());
+                }),
+        );
+        layout.add_child(DummyView);
+    }
+    layout.add_child(
+        LinearLayout::horizontal()
+            .child(TextView::new("Address "))
+            .child(ResizedView::new(
+                cursive::view::SizeConstraint::AtLeast(24),
+                cursive::view::SizeConstraint::Fixed(1),
+                EditView::new()
+                    .on_submit(connect_to_address)
+                    .with_name("address_box"),
+            )),
+    );
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Connect")
+            .button("Connect", |s| {
+                let address = s
+                    .find_name::<EditView>("address_box")
+                    .unwrap()
+                    .get_content();
+                connect_to_address(s, &address);
+            })
+            .button("Quit", |s| s.quit()),
     );
-    let login_dialog = cursive::views::Dialog::around(
+}
+fn connect_to_address(s: &mut Cursive, address: &str) {
+    let address = address.trim();
+    if address.is_empty() {
+        return;
+    }
+    s.pop_layer();
+    connect(s, Target::from_address(address));
+}
+/// Connects to `target` and asks the user to log in, or explains why the server can't be
+/// reached
+fn connect(s: &mut Cursive, target: Target) {
+    let conn = match &target.server_key {
+        Some(fingerprint) => Connection::with_pinned_key(&target.address, fingerprint),
+        None => Connection::new(&target.address),
+    };
+    match conn {
+        Ok(conn) => {
+            let username = target.username.clone();
+            s.with_user_data(|dat: &mut AppState| {
+                dat.main_connection = Some(conn);
+                dat.target = Some(target);
+            });
+            s.add_layer(login_dialog(username.as_deref()));
+        }
+        Err(e) => {
+            let text = format!("Could not connect to {}\n\n{e}", target.address);
+            s.add_layer(
+                Dialog::text(text)
+                    .title("Connection failed")
+                    .button("Retry", move |s| {
+                        s.pop_layer();
+                        connect(s, target.clone());
+                    })
+                    .button("Change server", |s| {
+                        s.pop_layer();
+                        connect_dialog(s);
+                    })
+                    .button("Quit", |s| s.quit()),
+            );
+        }
+    }
+}
+fn login_dialog(username: Option<&str>) -> impl View {
+    cursive::views::Dialog::around(
         cursive::views::LinearLayout::vertical()
             .child(TextView::new("Login").center())
             .child(
@@ -101,6 +220,7 @@ fn main() {
                         cursive::view::SizeConstraint::AtLeast(8),
                         cursive::view::SizeConstraint::Fixed(1),
                         EditView::new()
+                            .content(username.unwrap_or_default())
                             .on_submit(|s, text| {
                                 if !text.is_empty() {
                                     let _ = s.focus_name("pw_dialog");
@@ -126,16 +246,7 @@ fn main() {
             )
             .child(Button::new("Confirm", login).with_name("submit_btn")),
     )
-    .with_name("login_dialog");
-    c.add_layer(main_app);
-    c.add_layer(login_dialog);
-
-    c.run();
-}
-#[derive(Default)]
-struct AppState {
-    main_connection: Option<Connection>,
-    secondary_connection: Option<Connection>,
+    .with_name("login_dialog")
 }
 fn login(s: &mut Cursive) {
     let (username, password) = (
@@ -154,46 +265,806 @@ fn login(s: &mut Cursive) {
         return;
     }
     s.pop_layer();
-    let AppState {
-        main_connection: main_conn,
-        secondary_connection: recv_conn,
-        ..
-    } = s.take_user_data().unwrap();
-    let mut main_conn = main_conn.unwrap();
+    let mut state: AppState = s.take_user_data().unwrap();
+    let mut main_conn = state.main_connection.take().unwrap();
     let _ = main_conn.create_account(username.clone(), &password);
     let sink = s.cb_sink().to_owned();
 
-    main_conn.login(username.to_string(), &password).unwrap();
-    s.set_user_data(AppState {
-        main_connection: Some(main_conn),
-        ..Default::default()
-    });
+    let receiver = match main_conn
+        .login(username.to_string(), &password)
+        .map_err(|e| e.to_string())
+        .and_then(|()| main_conn.subscribe().map_err(|e| e.to_string()))
+    {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            state.main_connection = Some(main_conn);
+            s.set_user_data(state);
+            s.add_layer(login_dialog(Some(&username)));
+            s.add_layer(Dialog::info(format!("Login failed: {e}")));
+            return;
+        }
+    };
+    // Start with everyone we share a channel with
+    let members: Vec<String> = main_conn
+        .list_channels()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|chan| chan.members.contains(&username))
+        .flat_map(|chan| chan.members)
+        .filter(|member| *member != username)
+        .collect();
+    let has_key = main_conn.export_key().is_some();
+    state.main_connection = Some(main_conn);
+    let profile = state
+        .target
+        .as_ref()
+        .and_then(|target| target.profile.clone());
+    let saved = match profile {
+        Some(profile) if state.config.remember_username(&profile, &username) => {
+            state.config.save(&state.config_path)
+        }
+        _ => Ok(()),
+    };
+    s.set_user_data(state);
+    show_connected(s);
+    add_row(
+        s,
+        "*".to_string(),
+        "Open a conversation with /query or /join, switch between them with Ctrl-N and Ctrl-P \
+         or Alt-1 to Alt-9, /help lists the other commands"
+            .to_string(),
+    );
+    if let Err(e) = saved {
+        add_row(s, "!".to_string(), format!("<{e:#}>"));
+    }
+    if !has_key {
+        add_row(
+            s,
+            "!".to_string(),
+            "<This device does not hold your key for end-to-end encrypted messages, import it \
+             from another device with /key import, or replace it with /key reset>"
+                .to_string(),
+        );
+    }
+    watch(s, members);
 
     std::thread::Builder::new()
         .name("Message handler".to_string())
-        .spawn(move || {
-            let mut conn = recv_conn.unwrap();
-            conn.login(username.to_string(), &password).unwrap();
-            loop {
-                let msg = conn.recv_message().unwrap();
-                sink.send(Box::new(|s| {
-                    s.call_on_name("message_list", |e: &mut ListView| {
-                        e.add_child(
-                            format!(
-                                "[{}->{}]:",
-                                msg.sender,
-                                msg.recipients
-                                    .into_iter()
-                                    .reduce(|acc, e| format!("{acc},{e}"))
-                                    .unwrap(),
-                            ),
-                            TextView::new(msg.contents),
-                        );
-                    })
-                    .unwrap()
-                }))
-                .unwrap()
+        .spawn(move || loop {
+            let update: Box<dyn FnOnce(&mut Cursive) + Send> = match receiver.recv() {
+                Ok(Incoming::Message(msg)) => {
+                    let row = message_row(&msg, receiver.open(&msg.contents));
+                    Box::new(move |s| receive_message(s, msg, row))
+                }
+                Ok(Incoming::Receipt(receipt)) => Box::new(move |s| receive_receipt(s, receipt)),
+                Ok(Incoming::Presence(presence)) => {
+                    Box::new(move |s| show_contacts(s, vec![presence]))
+                }
+                Ok(Incoming::Disconnected) => {
+                    if !reconnect(&sink) {
+                        break;
+                    }
+                    continue;
+                }
+                Ok(Incoming::Reconnected) => Box::new(show_connected),
+                Err(RecvMessageError::Disconnected) => break,
+                Err(e) => {
+                    let text = format!("<{e}>");
+                    Box::new(move |s| add_row(s, "!".to_string(), text))
+                }
+            };
+            if sink.send(update).is_err() {
+                break;
             }
         })
         .unwrap();
 }
+/// Runs a command typed into the message box, or sends the text to the active conversation
+fn submit(s: &mut Cursive, text: &str) {
+    let result = match commands::parse(text) {
+        Input::Command { name, args } => commands().run(s, name, args).map_err(|e| e.to_string()),
+        Input::Message("") => return,
+        Input::Message(text) => {
+            commands::parse_recipients(&destination(s)).map(|recipients| send(s, recipients, text))
+        }
+    };
+    // Leave the text to fix up if it was rejected
+    match result {
+        Ok(()) => {
+            s.find_name::<EditView>("msg_box").unwrap().set_content("");
+        }
+        Err(e) => add_row(s, "!".to_string(), format!("<{e}>")),
+    }
+}
+/// Sends a message and adds it to the conversation with `recipients`
+fn send(s: &mut Cursive, recipients: Vec<String>, text: &str) {
+    // Direct messages are end-to-end encrypted, channels can't be as the server fans them out
+    let sealed = !recipients.iter().any(|r| types::is_channel_name(r));
+    let result = s
+        .with_user_data(|dat: &mut AppState| {
+            // Taken away while reconnecting
+            let Some(conn) = dat.main_connection.as_mut() else {
+                return Err("Not connected".to_string());
+            };
+            let sent = if sealed {
+                conn.send_encrypted_message(recipients.clone(), text.to_string())
+            } else {
+                conn.send_message(recipients.clone(), text.to_string())
+            };
+            let me = conn.username().unwrap_or_default().to_string();
+            sent.map(|sent| (me, sent)).map_err(|e| e.to_string())
+        })
+        .unwrap();
+    let (me, sent) = match result {
+        Ok(sent) => sent,
+        Err(e) => {
+            add_row(s, "!".to_string(), format!("<{e}>"));
+            return;
+        }
+    };
+    let destination = recipients.join(",");
+    let buffer = buffers::name(recipients, &me);
+    let timestamp = Local::now().timestamp_millis() as u64;
+    push_row(
+        s,
+        &buffer,
+        Row {
+            id: Some(sent.id),
+            label: message_label(timestamp, sent.id, &me, &destination, sealed),
+            contents: text.to_string(),
+        },
+    );
+    if !sent.rejected().is_empty() {
+        push_row(
+            s,
+            &buffer,
+            Row::notice(
+                format!("#{}", sent.id),
+                format!("not sent to unknown users {}", sent.rejected().join(", ")),
+            ),
+        );
+    }
+}
+/// Commands typed into the message box, starting with '/'
+fn commands() -> Registry<Cursive> {
+    let mut registry = Registry::new();
+    registry.register(Command {
+        name: "msg",
+        usage: "<users or #channel> <message>",
+        help: "Sends a message without switching conversation",
+        min_args: 2,
+        max_args: 2,
+        rest: true,
+        run: |s, args| {
+            let recipients = commands::parse_recipients(&args[0])?;
+            send(s, recipients, &args[1]);
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "query",
+        usage: "<users or #channel>",
+        help: "Opens a conversation with the given users or channel",
+        min_args: 1,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let recipients = commands::parse_recipients(&args[0])?;
+            open_buffer(s, recipients);
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "join",
+        usage: "<#channel>",
+        help: "Joins a channel, creating it if it doesn't exist yet",
+        min_args: 1,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let channel = channel_arg(s, args.first())?;
+            with_connection(s, |conn| conn.join_channel(channel.clone()))?;
+            add_row(s, "*".to_string(), format!("Joined {channel}"));
+            open_buffer(s, vec![channel]);
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "part",
+        usage: "[#channel]",
+        help: "Leaves a channel, the current one if none is given",
+        min_args: 0,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let channel = channel_arg(s, args.first())?;
+            with_connection(s, |conn| conn.part_channel(channel.clone()))?;
+            close_buffer(s, &channel);
+            add_row(s, "*".to_string(), format!("Left {channel}"));
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "close",
+        usage: "",
+        help: "Closes the current conversation, it opens again when a message arrives in it",
+        min_args: 0,
+        max_args: 0,
+        rest: false,
+        run: |s, _| {
+            let name = destination(s);
+            if name.is_empty() {
+                return Err("The status buffer can't be closed".to_string());
+            }
+            close_buffer(s, &name);
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "nick",
+        usage: "<username>",
+        help: "Asks an admin to rename your account, you are logged out once they approve",
+        min_args: 1,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let new_username = args[0].clone();
+            with_connection(s, |conn| conn.request_rename(new_username))?;
+            add_row(
+                s,
+                "*".to_string(),
+                format!("Asked to be renamed to {}", args[0]),
+            );
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "who",
+        usage: "[users or #channel]",
+        help: "Lists the members of a channel, or shows whether users are online",
+        min_args: 0,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let name = args.first().cloned().unwrap_or_else(|| destination(s));
+            if name.is_empty() {
+                return Err("Give a channel or user, or open a conversation".to_string());
+            }
+            if !name.starts_with('#') {
+                // Group conversations are named after everyone in them
+                let users = commands::parse_recipients(&name)?;
+                let presence = with_connection(s, |conn| conn.watch_presence(users.clone()))?;
+                let mut missing = Vec::new();
+                for user in users {
+                    match presence.iter().find(|presence| presence.username == user) {
+                        Some(presence) => add_row(s, user, presence_text(presence.status)),
+                        None => missing.push(user),
+                    }
+                }
+                if !missing.is_empty() {
+                    return Err(format!("No such user {}", missing.join(", ")));
+                }
+                return Ok(());
+            }
+            let channel = with_connection(s, |conn| conn.list_channels())?
+                .into_iter()
+                .find(|channel| channel.name == name)
+                .ok_or_else(|| format!("No such channel {name}"))?;
+            add_row(s, name, channel.members.join(", "));
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "away",
+        usage: "on|off",
+        help: "Shows you as away to others, or back again",
+        min_args: 1,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let away = match args[0].as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err("Usage: /away on|off".to_string()),
+            };
+            with_connection(s, |conn| conn.set_away(away))
+        },
+    });
+    registry.register(Command {
+        name: "sessions",
+        usage: "",
+        help: "Lists the devices logged in to your account",
+        min_args: 0,
+        max_args: 0,
+        rest: false,
+        run: |s, _| {
+            for session in with_connection(s, |conn| conn.sessions())? {
+                let device = session
+                    .device
+                    .map_or_else(|| "unnamed device".to_string(), |device| device.name);
+                let current = if session.current {
+                    ", this session"
+                } else {
+                    ""
+                };
+                add_row(
+                    s,
+                    format!("session {}", session.id),
+                    format!("{device}, idle {}s{current}", session.idle_secs),
+                );
+            }
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "revoke",
+        usage: "<session>",
+        help: "Logs out one of the sessions listed by /sessions",
+        min_args: 1,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let id = args[0]
+                .parse()
+                .map_err(|_| "Sessions are numbered, see /sessions".to_string())?;
+            with_connection(s, |conn| conn.revoke_session(id))
+        },
+    });
+    registry.register(Command {
+        name: "key",
+        usage: "export|import <key>|reset",
+        help: "Shows your key for end-to-end encrypted messages to import on another device, \
+               imports it, or replaces it if none of your devices has it any more",
+        min_args: 1,
+        max_args: 2,
+        rest: false,
+        run: |s, args| match (args[0].as_str(), args.get(1)) {
+            ("export", None) => {
+                let key = with_connection(s, |conn| {
+                    conn.export_key()
+                        .ok_or("This device does not hold your key either")
+                })?;
+                let hex: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+                add_row(s, "key".to_string(), hex);
+                Ok(())
+            }
+            ("import", Some(key)) => {
+                let key = commands::parse_key(key)?;
+                with_connection(s, |conn| conn.import_key(key))?;
+                add_row(s, "*".to_string(), "Imported your key".to_string());
+                Ok(())
+            }
+            ("reset", None) => {
+                with_connection(s, |conn| conn.reset_key())?;
+                add_row(
+                    s,
+                    "*".to_string(),
+                    "Replaced your key, import it on your other devices".to_string(),
+                );
+                Ok(())
+            }
+            _ => Err("Usage: /key export|import <key>|reset".to_string()),
+        },
+    });
+    registry.register(Command {
+        name: "fingerprint",
+        usage: "[user]",
+        help: "Shows the fingerprint of a user's key, or yours, to compare with theirs",
+        min_args: 0,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let username = match args.first() {
+                Some(username) => username.clone(),
+                None => with_connection(s, |conn| {
+                    conn.username().map(str::to_string).ok_or("Not logged in")
+                })?,
+            };
+            let fingerprint = with_connection(s, |conn| conn.fingerprint(&username))?
+                .ok_or_else(|| format!("{username} has not published a key"))?;
+            add_row(s, username, fingerprint);
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "trust",
+        usage: "<user>",
+        help: "Accepts the new key of a user who changed it, compare /fingerprint with them first",
+        min_args: 1,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            with_connection(s, |conn| conn.trust_key(&args[0]))?;
+            add_row(
+                s,
+                "*".to_string(),
+                format!("Trusting the key {} has now", args[0]),
+            );
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "quit",
+        usage: "",
+        help: "Closes the client",
+        min_args: 0,
+        max_args: 0,
+        rest: false,
+        run: |s, _| {
+            s.quit();
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "help",
+        usage: "[command]",
+        help: "Lists the commands, or describes one",
+        min_args: 0,
+        max_args: 1,
+        rest: false,
+        run: |s, args| {
+            let registry = commands();
+            let listed: Vec<&Command<Cursive>> = match args.first() {
+                Some(name) => vec![registry
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown command {name}"))?],
+                None => registry.commands().collect(),
+            };
+            for command in listed {
+                add_row(
+                    s,
+                    format!("/{} {}", command.name, command.usage),
+                    command.help.to_string(),
+                );
+            }
+            Ok(())
+        },
+    });
+    registry
+}
+/// The channel named by a command, or the active conversation if none is named
+fn channel_arg(s: &mut Cursive, arg: Option<&String>) -> Result<String, String> {
+    let channel = arg.cloned().unwrap_or_else(|| destination(s));
+    if types::is_channel_name(&channel) {
+        Ok(channel)
+    } else if arg.is_none() {
+        Err("This conversation is not a channel, give one".to_string())
+    } else {
+        Err(format!(
+            "{channel} is not a valid channel name, channel names are '#' followed by letters, \
+             digits, '-' or '_'"
+        ))
+    }
+}
+fn with_connection<T, E: std::fmt::Display>(
+    s: &mut Cursive,
+    f: impl FnOnce(&mut Connection) -> Result<T, E>,
+) -> Result<T, String> {
+    s.with_user_data(|dat: &mut AppState| {
+        let conn = dat.main_connection.as_mut()?;
+        Some(f(conn).map_err(|e| e.to_string()))
+    })
+    .flatten()
+    .unwrap_or_else(|| Err("Not connected".to_string()))
+}
+/// Where text typed into the message box goes, empty in the status buffer
+fn destination(s: &mut Cursive) -> String {
+    s.with_user_data(|dat: &mut AppState| dat.buffers.active().name.clone())
+        .filter(|name| name != buffers::STATUS)
+        .unwrap_or_default()
+}
+/// Opens the conversation with `recipients` and switches to it
+fn open_buffer(s: &mut Cursive, recipients: Vec<String>) {
+    let index = s.with_user_data(|dat: &mut AppState| {
+        let me = dat
+            .main_connection
+            .as_ref()
+            .and_then(Connection::username)
+            .unwrap_or_default();
+        let name = buffers::name(recipients, me);
+        dat.buffers.open(&name)
+    });
+    if let Some(index) = index {
+        switch_buffer(s, index);
+    }
+}
+fn close_buffer(s: &mut Cursive, name: &str) {
+    let active = s
+        .with_user_data(|dat: &mut AppState| {
+            let index = dat.buffers.find(name)?;
+            dat.buffers.close(index).then(|| dat.buffers.active_index())
+        })
+        .flatten();
+    match active {
+        Some(index) => switch_buffer(s, index),
+        None => show_buffer_list(s),
+    }
+}
+/// Switches to the buffer `by` places after the active one
+fn switch_by(s: &mut Cursive, by: isize) {
+    if let Some(index) = s.with_user_data(|dat: &mut AppState| dat.buffers.offset(by)) {
+        switch_buffer(s, index);
+    }
+}
+/// Shows buffer `index` in the message list, fetching its scrollback the first time and
+/// telling senders their messages in it were read
+fn switch_buffer(s: &mut Cursive, index: usize) {
+    let switched = s
+        .with_user_data(|dat: &mut AppState| {
+            let read = dat.buffers.switch(index)?;
+            let buffer = dat.buffers.active();
+            Some((buffer.name.clone(), buffer.history_loaded, read))
+        })
+        .flatten();
+    let Some((name, history_loaded, read)) = switched else {
+        return;
+    };
+    if name != buffers::STATUS {
+        if !history_loaded {
+            load_history(s, &name);
+        }
+        if !read.is_empty() {
+            let _ = with_connection(s, |conn| conn.mark_read(read));
+        }
+        watch(s, name.split(',').map(str::to_string).collect());
+    }
+    show_buffer(s);
+}
+/// Fills in the scrollback of a buffer, left empty if the server can't be asked
+fn load_history(s: &mut Cursive, name: &str) {
+    s.with_user_data(|dat: &mut AppState| {
+        // The server keeps scrollback per user and channel, not per group
+        let rows = if name.contains(',') {
+            Vec::new()
+        } else {
+            let conn = dat.main_connection.as_mut()?;
+            let entries = conn.history(name.to_string(), None, 50).ok()?;
+            entries
+                .into_iter()
+                .map(|entry| message_row(&entry.message, conn.open(&entry.message.contents)))
+                .collect()
+        };
+        dat.buffers.load_history(name, rows);
+        Some(())
+    });
+}
+/// Redraws the message list with the rows of the active buffer
+fn show_buffer(s: &mut Cursive) {
+    let rows = s
+        .with_user_data(|dat: &mut AppState| dat.buffers.active().rows.clone())
+        .unwrap_or_default();
+    s.call_on_name("message_list", |e: &mut ListView| {
+        e.clear();
+        for row in rows {
+            e.add_child(&row.label, TextView::new(row.contents));
+        }
+    });
+    show_buffer_list(s);
+}
+/// Redraws the sidebar, with the number of unread rows next to each buffer
+fn show_buffer_list(s: &mut Cursive) {
+    let (items, active) = s
+        .with_user_data(|dat: &mut AppState| {
+            let items: Vec<(String, usize)> = dat
+                .buffers
+                .iter()
+                .enumerate()
+                .map(|(index, buffer)| match buffer.unread {
+                    0 => (buffer.name.clone(), index),
+                    unread => (format!("{} ({unread})", buffer.name), index),
+                })
+                .collect();
+            (items, dat.buffers.active_index())
+        })
+        .unwrap_or_default();
+    s.call_on_name("buffer_list", |e: &mut SelectView<usize>| {
+        e.clear();
+        e.add_all(items);
+        let _ = e.set_selection(active);
+    });
+}
+/// Adds a row to the buffer `name`, and to the message list if it is the active buffer.
+/// Returns whether the row is shown.
+fn push_row(s: &mut Cursive, name: &str, row: Row) -> bool {
+    let shown = s
+        .with_user_data(|dat: &mut AppState| dat.buffers.push(name, row.clone()))
+        .unwrap_or_default();
+    if shown {
+        s.call_on_name("message_list", |e: &mut ListView| {
+            e.add_child(&row.label, TextView::new(row.contents));
+        });
+    }
+    show_buffer_list(s);
+    shown
+}
+/// Adds a row to the active buffer
+fn add_row(s: &mut Cursive, label: String, contents: String) {
+    let name = s
+        .with_user_data(|dat: &mut AppState| dat.buffers.active().name.clone())
+        .unwrap_or_default();
+    push_row(s, &name, Row::notice(label, contents));
+}
+/// Files a message under its conversation. Direct messages are only marked read once they have
+/// been shown.
+fn receive_message(s: &mut Cursive, msg: InboundMessage, row: Row) {
+    let name = s
+        .with_user_data(|dat: &mut AppState| {
+            let me = dat
+                .main_connection
+                .as_ref()
+                .and_then(Connection::username)
+                .unwrap_or_default();
+            buffers::conversation(&msg, me)
+        })
+        .unwrap_or_default();
+    let shown = push_row(s, &name, row);
+    if msg.channel.is_none() {
+        if shown {
+            let _ = with_connection(s, |conn| conn.mark_read(vec![msg.id]));
+        } else {
+            s.with_user_data(|dat: &mut AppState| dat.buffers.defer_read(&name, msg.id));
+        }
+    }
+    watch(s, vec![msg.sender]);
+}
+/// Adds a receipt to the buffer holding the message it is for, or the active one if the
+/// message isn't shown anywhere
+fn receive_receipt(s: &mut Cursive, receipt: Receipt) {
+    let name = s
+        .with_user_data(|dat: &mut AppState| {
+            dat.buffers
+                .find_message(receipt.id)
+                .unwrap_or(&dat.buffers.active().name)
+                .to_string()
+        })
+        .unwrap_or_default();
+    push_row(s, &name, receipt_row(&receipt));
+}
+/// Retries the connection with growing delays until it is back, or fails in a way retrying
+/// won't fix. The connection is taken from the UI meanwhile, so a server that is slow to
+/// answer doesn't freeze it. Returns `false` once the UI is gone.
+fn reconnect(sink: &CbSink) -> bool {
+    let (conn_tx, conn_rx) = mpsc::channel();
+    let taken = sink.send(Box::new(move |s| {
+        let conn = s
+            .with_user_data(|dat: &mut AppState| dat.main_connection.take())
+            .flatten();
+        let _ = conn_tx.send(conn);
+    }));
+    let mut conn = match taken.map(|()| conn_rx.recv()) {
+        Ok(Ok(Some(conn))) => conn,
+        Ok(Ok(None)) => return true,
+        _ => return false,
+    };
+    let mut backoff = Backoff::new();
+    let failure = loop {
+        let delay = backoff.next_delay();
+        let status = format!(
+            "Disconnected, reconnecting in {:.0}s",
+            delay.as_secs_f32().ceil()
+        );
+        if sink.send(Box::new(move |s| set_status(s, status))).is_err() {
+            return false;
+        }
+        std::thread::sleep(delay);
+        match conn.reconnect() {
+            Ok(()) => break None,
+            Err(e) if e.is_transient() => {}
+            Err(e) => break Some(format!("Disconnected: {e}")),
+        }
+    };
+    sink.send(Box::new(move |s| {
+        s.with_user_data(|dat: &mut AppState| dat.main_connection = Some(conn));
+        match failure {
+            Some(status) => set_status(s, status),
+            None => show_connected(s),
+        }
+    }))
+    .is_ok()
+}
+/// Shows where we are connected to and who as in the status line
+fn show_connected(s: &mut Cursive) {
+    let status = s
+        .with_user_data(|dat: &mut AppState| {
+            let address = dat.target.as_ref()?.address.clone();
+            let conn = dat.main_connection.as_ref()?;
+            Some(match conn.username() {
+                Some(username) => format!("Connected to {address} as {username}"),
+                None => format!("Connected to {address}"),
+            })
+        })
+        .flatten()
+        .unwrap_or_default();
+    set_status(s, status);
+}
+fn set_status(s: &mut Cursive, status: String) {
+    s.call_on_name("status", |e: &mut TextView| e.set_content(status));
+}
+/// Adds users to the user list, unless they are already in it or are channels
+fn watch(s: &mut Cursive, usernames: Vec<String>) {
+    let presence = s
+        .with_user_data(|dat: &mut AppState| {
+            let usernames: Vec<String> = usernames
+                .into_iter()
+                .filter(|usr| !types::is_channel_name(usr) && !dat.contacts.contains_key(usr))
+                .collect();
+            match dat.main_connection.as_mut() {
+                Some(conn) if !usernames.is_empty() => {
+                    conn.watch_presence(usernames).unwrap_or_default()
+                }
+                _ => Vec::new(),
+            }
+        })
+        .unwrap_or_default();
+    show_contacts(s, presence);
+}
+/// Records presence updates and redraws the user list
+fn show_contacts(s: &mut Cursive, presence: Vec<Presence>) {
+    let contacts = s
+        .with_user_data(|dat: &mut AppState| {
+            for presence in presence {
+                dat.contacts.insert(presence.username, presence.status);
+            }
+            dat.contacts.clone()
+        })
+        .unwrap_or_default();
+    s.call_on_name("user_list", |e: &mut ListView| {
+        e.clear();
+        for (username, status) in contacts {
+            e.add_child(&username, TextView::new(presence_text(status)));
+        }
+    });
+}
+fn presence_text(status: PresenceStatus) -> String {
+    match status {
+        PresenceStatus::Online => "online".to_string(),
+        PresenceStatus::Away => "away".to_string(),
+        PresenceStatus::Offline {
+            last_seen: Some(last_seen),
+        } => match Local.timestamp_millis_opt(last_seen as i64) {
+            LocalResult::Single(time) => time.format("seen %d/%m %H:%M").to_string(),
+            _ => "offline".to_string(),
+        },
+        PresenceStatus::Offline { last_seen: None } => "offline".to_string(),
+    }
+}
+/// The row showing a message in its buffer
+fn message_row(msg: &InboundMessage, contents: Result<String, RecvMessageError>) -> Row {
+    let destination = msg
+        .channel
+        .clone()
+        .unwrap_or_else(|| msg.recipients.join(","));
+    let sealed = matches!(msg.contents, MessageBody::Sealed(_));
+    Row {
+        id: Some(msg.id),
+        label: message_label(msg.timestamp, msg.id, &msg.sender, &destination, sealed),
+        contents: contents.unwrap_or_else(|e| format!("<{e}>")),
+    }
+}
+fn message_label(timestamp: u64, id: u64, sender: &str, destination: &str, sealed: bool) -> String {
+    let time = match Local.timestamp_millis_opt(timestamp as i64) {
+        LocalResult::Single(time) if timestamp != 0 => time.format("%H:%M").to_string(),
+        _ => "--:--".to_string(),
+    };
+    format!(
+        "{} #{} [{}->{}]{}:",
+        time,
+        id,
+        sender,
+        destination,
+        if sealed { " (e2e)" } else { "" }
+    )
+}
+/// The row showing a receipt in the buffer of the message it is for
+fn receipt_row(receipt: &Receipt) -> Row {
+    let status = match receipt.status {
+        DeliveryStatus::Read => "read by",
+        DeliveryStatus::Delivered => "delivered to",
+        DeliveryStatus::Queued => "queued for",
+        DeliveryStatus::UnknownUser => "no such user",
+    };
+    Row::notice(
+        format!("#{}", receipt.id),
+        format!("{} {}", status, receipt.recipient),
+    )
+}
@@ -0,0 +1,112 @@
+use bytes::{Buf, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{convert::TryInto, io, marker::PhantomData};
+use tokio_util::codec::{Decoder, Encoder};
+use types::frame::{self, HEADER_LEN};
+
+/// Frames packets as described in [`types::frame`], the same way the client does
+pub struct PacketCodec<S, R> {
+    max_packet_size: u64,
+    pd: PhantomData<(S, R)>,
+}
+impl<S, R> PacketCodec<S, R> {
+    pub fn new(max_packet_size: u64) -> Self {
+        Self {
+            max_packet_size,
+            pd: PhantomData,
+        }
+    }
+}
+impl<S, R: DeserializeOwned> Decoder for PacketCodec<S, R> {
+    type Item = R;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<R>, io::Error> {
+        let Some(header) = src.get(..HEADER_LEN) else {
+            return Ok(None);
+        };
+        let len = frame::packet_len(header.try_into().unwrap(), self.max_packet_size)?;
+        if src.len() < HEADER_LEN + len {
+            // Room for the rest of the packet, so it's read without growing the buffer again
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let packet = src.split_to(len);
+        frame::decode(&packet).map(Some)
+    }
+}
+impl<S: Serialize, R> Encoder<S> for PacketCodec<S, R> {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: S, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut frame = Vec::new();
+        frame::encode(&packet, &mut frame)?;
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::{CPacket, SError, SPacket};
+
+    fn packet() -> SPacket {
+        SPacket::Error(SError::UnsupportedVersion { min: 1, max: 2 })
+    }
+
+    #[test]
+    fn decodes_what_the_client_writes() {
+        let mut written = Vec::new();
+        frame::write(&mut written, &packet()).unwrap();
+        frame::write(&mut written, &packet()).unwrap();
+        let mut codec = PacketCodec::<CPacket, SPacket>::new(1024);
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        // Byte by byte, as if every read came up short
+        for byte in written {
+            src.extend_from_slice(&[byte]);
+            if let Some(packet) = codec.decode(&mut src).unwrap() {
+                decoded.push(packet);
+            }
+        }
+        assert_eq!(decoded.len(), 2);
+        assert!(src.is_empty());
+        assert!(matches!(
+            decoded[0],
+            SPacket::Error(SError::UnsupportedVersion { min: 1, max: 2 })
+        ));
+    }
+
+    #[test]
+    fn client_reads_what_is_encoded() {
+        let mut codec = PacketCodec::<SPacket, CPacket>::new(1024);
+        let mut dst = BytesMut::new();
+        codec.encode(packet(), &mut dst).unwrap();
+        codec.encode(packet(), &mut dst).unwrap();
+        let mut reader = &dst[..];
+        for _ in 0..2 {
+            let packet: SPacket = frame::read(&mut reader, 1024).unwrap();
+            assert!(matches!(
+                packet,
+                SPacket::Error(SError::UnsupportedVersion { min: 1, max: 2 })
+            ));
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn oversized_packets_are_refused_before_they_arrive() {
+        let mut codec = PacketCodec::<SPacket, CPacket>::new(16);
+        let mut src = BytesMut::from(&17u32.to_le_bytes()[..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let mut codec = PacketCodec::<SPacket, CPacket>::new(16);
+        let mut src = BytesMut::from(&[4, 0, 0, 0, 0xff, 0xff, 0xff, 0xff][..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
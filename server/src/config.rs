@@ -0,0 +1,192 @@
+use anyhow::{bail, Context};
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "irc_server.toml";
+
+#[derive(Parser, Debug)]
+#[command(about = "IRC server")]
+pub struct Args {
+    /// Path to a TOML config file, defaults to ./irc_server.toml if it exists
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, may be given multiple times
+    #[arg(short, long)]
+    pub bind: Vec<String>,
+    /// Path of the PEM encoded server identity key, generated on first run if missing
+    #[arg(long)]
+    pub identity_key: Option<PathBuf>,
+    /// Size in bits of the server RSA key when generating a new identity key
+    #[arg(long)]
+    pub key_bits: Option<usize>,
+    /// Path of the storage log, or ":memory:" to keep everything in memory
+    #[arg(long)]
+    pub storage: Option<String>,
+    /// Maximum number of undelivered messages queued per user
+    #[arg(long)]
+    pub max_queued_messages: Option<usize>,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long)]
+    pub log_level: Option<LevelFilter>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<String>,
+    pub identity_key: PathBuf,
+    pub key_bits: usize,
+    pub storage: String,
+    pub max_queued_messages: usize,
+    /// Messages kept per user per conversation for scrollback, 0 disables history
+    pub history_per_conversation: usize,
+    /// Connections sending a larger packet are dropped
+    pub max_packet_size: u64,
+    /// Session tokens unused for this many seconds expire
+    pub token_idle_secs: u64,
+    /// Sessions end this many seconds after the handshake, however often they are used or their
+    /// token is refreshed
+    pub token_lifetime_secs: u64,
+    pub username: UsernameRules,
+    /// Accounts that can be created from one IP address per hour, 0 for no limit
+    pub max_accounts_per_hour: usize,
+    /// Users allowed to review rename requests, their accounts have to exist before they are
+    /// listed as the names are reserved
+    pub admins: Vec<String>,
+    pub log_level: LevelFilter,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec!["0.0.0.0:65432".to_string()],
+            identity_key: PathBuf::from("irc_server_key.pem"),
+            key_bits: 2048,
+            storage: "irc_server.log".to_string(),
+            max_queued_messages: 1000,
+            history_per_conversation: 1000,
+            max_packet_size: 1024 * 1024,
+            token_idle_secs: 30 * 60,
+            token_lifetime_secs: 24 * 60 * 60,
+            username: UsernameRules::default(),
+            max_accounts_per_hour: 10,
+            admins: Vec::new(),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameRules {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Characters allowed in addition to alphanumerics
+    pub extra_chars: String,
+}
+impl Default for UsernameRules {
+    fn default() -> Self {
+        Self {
+            min_length: 1,
+            max_length: 32,
+            extra_chars: String::new(),
+        }
+    }
+}
+impl UsernameRules {
+    pub fn is_valid(&self, username: &str) -> bool {
+        let len = username.chars().count();
+        len >= self.min_length
+            && len <= self.max_length
+            && username
+                .chars()
+                .all(|chr| chr.is_alphanumeric() || self.extra_chars.contains(chr))
+    }
+}
+
+impl Config {
+    pub fn load(args: Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        if !args.bind.is_empty() {
+            config.bind = args.bind;
+        }
+        if let Some(identity_key) = args.identity_key {
+            config.identity_key = identity_key;
+        }
+        if let Some(key_bits) = args.key_bits {
+            config.key_bits = key_bits;
+        }
+        if let Some(storage) = args.storage {
+            config.storage = storage;
+        }
+        if let Some(max_queued_messages) = args.max_queued_messages {
+            config.max_queued_messages = max_queued_messages;
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn token_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.token_idle_secs)
+    }
+    pub fn token_lifetime(&self) -> Duration {
+        Duration::from_secs(self.token_lifetime_secs)
+    }
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.bind.is_empty() {
+            bail!("At least one bind address is required");
+        }
+        for addr in &self.bind {
+            addr.parse::<SocketAddr>()
+                .with_context(|| format!("Invalid bind address {addr:?}"))?;
+        }
+        if !(1024..=16384).contains(&self.key_bits) {
+            bail!(
+                "key_bits must be between 1024 and 16384, got {}",
+                self.key_bits
+            );
+        }
+        if self.storage.is_empty() {
+            bail!("storage must be a file path or \":memory:\"");
+        }
+        if self.max_queued_messages == 0 {
+            bail!("max_queued_messages must be at least 1");
+        }
+        if self.max_packet_size < 4096 {
+            bail!("max_packet_size must be at least 4096 bytes to fit a handshake");
+        }
+        if self.token_idle_secs == 0 || self.token_lifetime_secs == 0 {
+            bail!("token_idle_secs and token_lifetime_secs must be at least 1");
+        }
+        if self.username.min_length == 0 || self.username.min_length > self.username.max_length {
+            bail!(
+                "username lengths must satisfy 1 <= min_length <= max_length, got {}..={}",
+                self.username.min_length,
+                self.username.max_length
+            );
+        }
+        if self.username.extra_chars.contains([',', '#']) {
+            bail!("username.extra_chars may not contain ',' or '#'");
+        }
+        Ok(())
+    }
+}
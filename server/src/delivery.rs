@@ -0,0 +1,200 @@
+use log::{debug, error};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use types::{enc::AesData, SPacket, SRecvMessage};
+
+use crate::{presence, receipts, storage};
+
+/// A subscribed session's delivery task
+struct Subscriber {
+    wake: UnboundedSender<()>,
+    writer: Arc<PacketWriter>,
+    aes_key: Vec<u8>,
+}
+/// Delivery tasks of a user's subscribed sessions, keyed by session token
+type Sessions = HashMap<u128, Subscriber>;
+/// Wakes the delivery task of every subscribed session, keyed by username
+static SUBSCRIBERS: LazyLock<Mutex<HashMap<String, Sessions>>> =
+    LazyLock::new(|| HashMap::new().into());
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Sending half of a client connection, shared between its request handler and delivery task.
+/// Packets are queued for the connection's writer task so sending never blocks, `None` tells
+/// the writer task to hang up.
+pub struct PacketWriter {
+    packets: UnboundedSender<Option<SPacket>>,
+    id: u64,
+}
+#[derive(Debug)]
+pub struct Disconnected;
+impl PacketWriter {
+    pub fn new(packets: UnboundedSender<Option<SPacket>>) -> Self {
+        Self {
+            packets,
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+    /// Uniquely identifies the connection for the lifetime of the server
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn send(&self, packet: SPacket) -> Result<(), Disconnected> {
+        self.packets.send(Some(packet)).map_err(|_| Disconnected)
+    }
+    /// Closes the connection once the packets queued so far are sent
+    pub fn close(&self) {
+        let _ = self.packets.send(None);
+    }
+}
+
+/// Starts pushing every message, receipt and presence change queued for `username` down
+/// `writer`, until the session is unsubscribed or the client disconnects. Each device is sent
+/// each message once, however many sessions it has. Receipts and presence changes are
+/// [`broadcast`] to every session, those queued while none was subscribed go to the first.
+pub fn subscribe(
+    username: String,
+    token: u128,
+    device: u128,
+    writer: Arc<PacketWriter>,
+    aes_key: Vec<u8>,
+) {
+    let (notify_tx, mut notify_rx) = mpsc::unbounded_channel();
+    // Deliver anything queued while the user was away
+    let _ = notify_tx.send(());
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .entry(username.clone())
+        .or_default()
+        .insert(
+            token,
+            Subscriber {
+                wake: notify_tx,
+                writer: writer.clone(),
+                aes_key: aes_key.clone(),
+            },
+        );
+
+    tokio::spawn(async move {
+        // Ends once `unsubscribe` drops the sender
+        while notify_rx.recv().await.is_some() {
+            loop {
+                let msg = match storage().next_message(&username, device) {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Delivery to {username} stopped, storage failure: {e}");
+                        return;
+                    }
+                };
+                let (id, direct) = (msg.id, msg.channel.is_none());
+                let packet = |message| SRecvMessage::NextMsg { message };
+                if push(&writer, &username, &aes_key, msg, packet).is_err() {
+                    return;
+                }
+                if direct {
+                    receipts::delivered(id, &username);
+                }
+            }
+            while let Some(receipt) = receipts::pop(&username) {
+                let packet = |receipt| SRecvMessage::Receipt { receipt };
+                if push(&writer, &username, &aes_key, receipt, packet).is_err() {
+                    return;
+                }
+            }
+            while let Some(presence) = presence::pop(&username) {
+                let packet = |presence| SRecvMessage::Presence { presence };
+                if push(&writer, &username, &aes_key, presence, packet).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+/// Sends `item` to every session subscribed as `username`, or returns it if there are none so
+/// it can be queued for the next to subscribe
+pub fn broadcast<T: Serialize + DeserializeOwned + Clone>(
+    username: &str,
+    item: T,
+    packet: impl Fn(AesData<T>) -> SRecvMessage,
+) -> Option<T> {
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+    let Some(sessions) = subscribers.get(username) else {
+        return Some(item);
+    };
+    for subscriber in sessions.values() {
+        let _ = push(
+            &subscriber.writer,
+            username,
+            &subscriber.aes_key,
+            item.clone(),
+            &packet,
+        );
+    }
+    None
+}
+/// Encrypts `item` into the packet built by `packet` and sends it. Items that fail to encrypt
+/// are logged and skipped, only a disconnect is an error.
+fn push<T: Serialize + DeserializeOwned>(
+    writer: &PacketWriter,
+    username: &str,
+    aes_key: &[u8],
+    item: T,
+    packet: impl FnOnce(AesData<T>) -> SRecvMessage,
+) -> Result<(), Disconnected> {
+    let item = match AesData::new(item, aes_key) {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Failed to encrypt push for {username}: {e}");
+            return Ok(());
+        }
+    };
+    writer
+        .send(SPacket::RecvMessage(packet(item)))
+        .inspect_err(|_| debug!("Delivery to {username} stopped, client disconnected"))
+}
+/// Stops deliveries to a session that asked for it, e.g. by logging out
+pub fn unsubscribe(username: &str, token: u128) {
+    remove(username, token);
+}
+/// Stops deliveries to a session the server ended without being asked to, and hangs up on it.
+/// The client would otherwise wait for pushes that never come, now it reconnects instead.
+pub fn kick(username: &str, token: u128) {
+    if let Some(subscriber) = remove(username, token) {
+        debug!("Hanging up on {username}, their subscribed session ended");
+        subscriber.writer.close();
+    }
+}
+fn remove(username: &str, token: u128) -> Option<Subscriber> {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    let sessions = subscribers.get_mut(username)?;
+    let subscriber = sessions.remove(&token);
+    if sessions.is_empty() {
+        subscribers.remove(username);
+    }
+    subscriber
+}
+/// Moves the subscription of a session whose token was rotated over to its new token
+pub fn rekey(username: &str, old_token: u128, new_token: u128) {
+    if let Some(sessions) = SUBSCRIBERS.lock().unwrap().get_mut(username) {
+        if let Some(subscriber) = sessions.remove(&old_token) {
+            sessions.insert(new_token, subscriber);
+        }
+    }
+}
+/// Wakes every session subscribed as `username` so it delivers newly queued messages
+pub fn notify(username: &str) {
+    if let Some(sessions) = SUBSCRIBERS.lock().unwrap().get(username) {
+        for subscriber in sessions.values() {
+            let _ = subscriber.wake.send(());
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::io;
+use thiserror::Error;
+use types::{
+    enc::{AesError, RsaError},
+    SAccount, SError, SPacket,
+};
+
+use crate::delivery::Disconnected;
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Client disconnected")]
+    Disconnected,
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Session is not logged in")]
+    NotLoggedIn,
+    #[error("Packet sent before handshake")]
+    NotHandshaken,
+    #[error("Replayed or out of sequence packet")]
+    Replayed,
+    #[error("Request sent without a sequence number")]
+    Unsequenced,
+    #[error("Packet not allowed inside a sequenced packet")]
+    Malformed,
+    #[error("RSA failure: {0}")]
+    Rsa(#[from] RsaError),
+    #[error("AES failure: {0}")]
+    Aes(#[from] AesError),
+    #[error("Storage failure: {0}")]
+    Storage(#[from] io::Error),
+}
+impl From<Disconnected> for ServerError {
+    fn from(_: Disconnected) -> Self {
+        Self::Disconnected
+    }
+}
+impl ServerError {
+    /// The reply telling the client what went wrong, `None` if the connection should be closed
+    pub fn reply(&self) -> Option<SPacket> {
+        match self {
+            ServerError::Disconnected => None,
+            ServerError::InvalidToken => Some(SPacket::Account(SAccount::InvalidToken)),
+            ServerError::NotLoggedIn => Some(SPacket::Account(SAccount::NotLoggedIn)),
+            ServerError::NotHandshaken => Some(SPacket::Error(SError::NotHandshaken)),
+            ServerError::Replayed => Some(SPacket::Error(SError::Replayed)),
+            ServerError::Unsequenced => Some(SPacket::Error(SError::Unsequenced)),
+            ServerError::Malformed => Some(SPacket::Error(SError::MalformedPacket)),
+            ServerError::Rsa(_) | ServerError::Aes(_) => {
+                Some(SPacket::Error(SError::DecryptionFailed))
+            }
+            ServerError::Storage(_) => Some(SPacket::Error(SError::Internal)),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Context;
+use log::info;
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    rand_core, RsaPrivateKey,
+};
+use std::path::Path;
+
+/// Loads the long-lived server key, generating and saving a new one on first run
+pub fn load_or_generate(path: &Path, key_bits: usize) -> anyhow::Result<RsaPrivateKey> {
+    if path.exists() {
+        return RsaPrivateKey::read_pkcs8_pem_file(path)
+            .with_context(|| format!("Failed to read identity key {}", path.display()));
+    }
+    info!(
+        "No identity key at {}, generating a {key_bits} bit key, please allow a few seconds for this to happen",
+        path.display()
+    );
+    let key = RsaPrivateKey::new(&mut rand_core::OsRng, key_bits)
+        .context("Failed to generate identity key")?;
+    let pem = key
+        .to_pkcs8_pem(LineEnding::LF)
+        .context("Failed to encode identity key")?;
+    write_private(path, pem.as_bytes())
+        .with_context(|| format!("Failed to write identity key {}", path.display()))?;
+    Ok(key)
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
@@ -1,263 +1,1127 @@
-use net_message::asymmetric::AsymmetricTcpStream;
+use clap::Parser;
+use codec::PacketCodec;
+use config::Config;
+use delivery::PacketWriter;
+use error::ServerError;
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
 use rand::Rng;
-use rsa::{rand_core, RsaPrivateKey, RsaPublicKey};
+use rate_limit::RateLimiter;
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use std::{
-    collections::{HashMap, VecDeque},
-    net::TcpListener,
-    sync::{LazyLock, RwLock},
-    time::Duration,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    process::ExitCode,
+    sync::{Arc, LazyLock, OnceLock, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use storage::{FileStorage, MemoryStorage, Storage};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
 use types::{
-    enc::{AesData, RsaData},
-    CPacket, Credentials, InboundMessage, OutboundMessage, SAccount, SPacket, SSendMessage,
+    enc::{self, AesData, RsaData},
+    CPacket, Credentials, DeliveryStatus, Device, HandshakeTranscript, InboundMessage, MessageBody,
+    OutboundMessage, PresenceStatus, SAccount, SChannel, SError, SPacket, SPresence, SRecvMessage,
+    SSendMessage, SequencedPacket, SessionInfo,
 };
 
+mod codec;
+mod config;
+mod delivery;
+mod error;
+mod identity;
+mod password;
+mod presence;
+mod rate_limit;
+mod receipts;
+mod storage;
+
 static TOKEN_MAP: LazyLock<RwLock<HashMap<u128, TokenData>>> =
     LazyLock::new(|| HashMap::new().into());
-static ACCOUNT_MAP: LazyLock<RwLock<HashMap<String, String>>> =
-    LazyLock::new(|| HashMap::new().into());
-static MESSAGE_MAP: LazyLock<RwLock<HashMap<String, VecDeque<InboundMessage>>>> =
-    LazyLock::new(|| HashMap::new().into());
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+static CONFIG: OnceLock<Config> = OnceLock::new();
+static IDENTITY_KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+/// Accounts created from each address, hashing a password is too slow to do for anyone who asks
+static ACCOUNT_CREATIONS: LazyLock<RateLimiter<IpAddr>> =
+    LazyLock::new(|| RateLimiter::new(config().max_accounts_per_hour, Duration::from_secs(3600)));
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most history entries returned per request
+const HISTORY_PAGE_LIMIT: usize = 200;
+/// Device shared by every client subscribing without naming its device
+const LEGACY_DEVICE: u128 = 0;
+/// How often expired session tokens are swept out of `TOKEN_MAP`
+const TOKEN_REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+fn storage() -> &'static dyn Storage {
+    STORAGE.get().unwrap().as_ref()
+}
+fn config() -> &'static Config {
+    CONFIG.get().unwrap()
+}
+fn identity_key() -> &'static RsaPrivateKey {
+    IDENTITY_KEY.get().unwrap()
+}
 
 #[derive(Clone, Debug)]
 struct TokenData {
     username: Option<String>,
     rsa_key: RsaPublicKey,
     aes_key: Vec<u8>,
+    /// Id of the connection that handshook, the token is rejected on any other connection
+    connection: u64,
+    /// Address the connection came from
+    peer: IpAddr,
+    /// When the session began, kept when the token is refreshed so the lifetime caps the session
+    created: Instant,
+    last_used: Instant,
+    /// Sequence number of the last request accepted with this token
+    last_seq: u64,
+    /// Set by the client, the user shows as away once all their sessions are
+    away: bool,
+    /// The device the session subscribed as, if it named one
+    device: Option<Device>,
+    /// Subscribed sessions wait for pushes rather than sending requests, so they never go idle
+    subscribed: bool,
+}
+impl TokenData {
+    fn is_expired(&self, now: Instant) -> bool {
+        (!self.subscribed && now.duration_since(self.last_used) >= config().token_idle_timeout())
+            || now.duration_since(self.created) >= config().token_lifetime()
+    }
 }
 
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:65432").unwrap();
-    for client in listener.incoming().flatten() {
-        std::thread::spawn(|| {
-            client.set_nonblocking(false).unwrap();
-            client.set_read_timeout(None).unwrap();
-            let mut stream = AsymmetricTcpStream::<SPacket, CPacket>::new_unchecked(client);
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config = match Config::load(config::Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
+
+    let storage: Box<dyn Storage> = if config.storage == ":memory:" {
+        Box::new(MemoryStorage::default())
+    } else {
+        match FileStorage::open(&config.storage) {
+            Ok(storage) => Box::new(storage),
+            Err(e) => {
+                error!("Failed to open storage {}: {e}", config.storage);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    for admin in &config.admins {
+        if storage.get_account(admin).is_none() {
+            warn!("Admin {admin} has no account and can't get one, create it before listing it");
+        }
+    }
+    let _ = STORAGE.set(storage);
+
+    let identity_key = match identity::load_or_generate(&config.identity_key, config.key_bits) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("{e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    info!(
+        "Server key fingerprint: {}",
+        types::enc::key_fingerprint(&identity_key.to_public_key())
+    );
+    let _ = IDENTITY_KEY.set(identity_key);
+
+    let mut listeners = Vec::new();
+    for addr in &config.bind {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("Listening on {addr}");
+                listeners.push(listener);
+            }
+            Err(e) => {
+                error!("Failed to bind {addr}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let _ = CONFIG.set(config);
+    tokio::spawn(reap_tokens());
 
-            println!("Generating server RSA keys, please allow a few seconds for this to happen");
-            let priv_key = RsaPrivateKey::new(&mut rand_core::OsRng, 2048).unwrap();
-            println!("Generated keys");
+    futures::future::join_all(listeners.into_iter().map(serve)).await;
+    ExitCode::SUCCESS
+}
+async fn reap_tokens() {
+    let mut interval = tokio::time::interval(TOKEN_REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        remove_tokens(|usr| usr.is_expired(now));
+    }
+}
+/// Removes every session matching `pred`, hanging up on those that were subscribed
+fn remove_tokens(pred: impl Fn(&TokenData) -> bool) {
+    let mut removed = Vec::new();
+    TOKEN_MAP.write().unwrap().retain(|token, usr| {
+        if !pred(usr) {
+            return true;
+        }
+        if let Some(username) = usr.username.take() {
+            removed.push((username, *token));
+        }
+        false
+    });
+    for (username, token) in &removed {
+        delivery::kick(username, *token);
+    }
+    let usernames: HashSet<_> = removed.into_iter().map(|(username, _)| username).collect();
+    for username in usernames {
+        refresh_presence(&username);
+    }
+}
+/// Recomputes the presence of `username` from their live sessions
+fn refresh_presence(username: &str) {
+    let now = Instant::now();
+    let away: Vec<bool> = TOKEN_MAP
+        .read()
+        .unwrap()
+        .values()
+        .filter(|usr| usr.username.as_deref() == Some(username) && !usr.is_expired(now))
+        .map(|usr| usr.away)
+        .collect();
+    let status = if away.is_empty() {
+        PresenceStatus::Offline {
+            last_seen: Some(unix_millis()),
+        }
+    } else if away.iter().all(|away| *away) {
+        PresenceStatus::Away
+    } else {
+        PresenceStatus::Online
+    };
+    presence::update(username, status);
+}
+/// Milliseconds since the Unix epoch (UTC), as used for timestamps on the wire
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((client, addr)) => {
+                debug!("Accepted connection from {addr}");
+                tokio::spawn(handle_connection(client, addr));
+            }
+            Err(e) => warn!("Failed to accept connection: {e}"),
+        }
+    }
+}
+async fn handle_connection(client: TcpStream, addr: SocketAddr) {
+    let (read_half, write_half) = client.into_split();
+    let mut reader = FramedRead::new(
+        read_half,
+        PacketCodec::<SPacket, CPacket>::new(config().max_packet_size),
+    );
+    let mut writer = FramedWrite::new(
+        write_half,
+        PacketCodec::<SPacket, CPacket>::new(config().max_packet_size),
+    );
+    let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(Some(packet)) = packets_rx.recv().await {
+            if writer.send(packet).await.is_err() {
+                break;
+            }
+        }
+    });
+    let stream = Arc::new(PacketWriter::new(packets_tx));
+    let priv_key = identity_key();
+    // Connections that never complete a handshake are dropped rather than held open forever
+    let mut handshake_deadline = Some(Instant::now() + HANDSHAKE_TIMEOUT);
 
-            while let Ok(pack) = stream.read() {
-                println!("{pack:?}");
-                match pack {
-                    CPacket::Handshake { client_key } => {
-                        handshake(&mut stream, &client_key, &priv_key)
-                    }
-                    CPacket::Account(c_account) => match c_account {
-                        types::CAccount::Login { token, creds } => {
-                            login(&mut stream, &priv_key, token, creds)
-                        }
-                        types::CAccount::Create { token, creds } => {
-                            create_account(&mut stream, &priv_key, token, creds)
-                        }
-                        types::CAccount::Logout { token } => logout(&mut stream, &priv_key, token),
-                    },
-                    CPacket::SendMessage(csend_message) => match csend_message {
-                        types::CSendMessage::Send { token, message } => {
-                            send_msg(&mut stream, &priv_key, token, message)
-                        }
-                    },
-                    CPacket::RecvMessage(crecv_message) => match crecv_message {
-                        types::CRecvMessage::FetchNext { token } => {
-                            recv_msg(&mut stream, &priv_key, token)
-                        }
-                    },
+    loop {
+        let next = match handshake_deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, reader.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    debug!("Client did not handshake in time");
+                    break;
                 }
+            },
+            None => reader.next().await,
+        };
+        let pack = match next {
+            Some(Ok(pack)) => pack,
+            Some(Err(e)) => {
+                // The reader stops at its first error, so the connection can't carry on
+                warn!("Closing connection after malformed packet: {e}");
+                let _ = stream.send(SPacket::Error(SError::MalformedPacket));
+                break;
             }
-        });
+            None => break,
+        };
+        debug!("{pack:?}");
+        let result = match pack {
+            CPacket::Handshake {
+                client_key,
+                version,
+                capabilities,
+            } if handshake_deadline.is_some() => {
+                if version < types::MIN_PROTOCOL_VERSION {
+                    warn!("Client speaks unsupported protocol version {version}");
+                    let _ = stream.send(SPacket::Error(SError::UnsupportedVersion {
+                        min: types::MIN_PROTOCOL_VERSION,
+                        max: types::PROTOCOL_VERSION,
+                    }));
+                    break;
+                }
+                handshake_deadline = None;
+                handshake(
+                    &stream,
+                    addr.ip(),
+                    &client_key,
+                    priv_key,
+                    version,
+                    capabilities,
+                )
+            }
+            _ if handshake_deadline.is_some() => Err(ServerError::NotHandshaken),
+            CPacket::Sequenced { token, packet } => unseal(&stream, priv_key, token, packet)
+                .and_then(|(token, pack)| dispatch(&stream, token, pack)),
+            // Including a second handshake, which would hand out another token under the same
+            // session ID
+            _ => Err(ServerError::Unsequenced),
+        };
+        if let Err(e) = result {
+            debug!("Request failed: {e}");
+            if let ServerError::Storage(_) = e {
+                error!("{e}");
+            }
+            let Some(reply) = e.reply() else {
+                break;
+            };
+            if stream.send(reply).is_err() {
+                break;
+            }
+        }
+    }
+    // Tokens die with their connection
+    remove_tokens(|usr| usr.connection == stream.id());
+}
+/// Handles a request that was unwrapped from a sequenced packet carrying `token`
+fn dispatch(stream: &Arc<PacketWriter>, token: u128, pack: CPacket) -> Result<(), ServerError> {
+    match pack {
+        CPacket::Account(c_account) => match c_account {
+            types::CAccount::Login { creds } => login(stream, token, creds),
+            types::CAccount::Create { creds } => create_account(stream, token, creds),
+            types::CAccount::Logout => logout(stream, token),
+            types::CAccount::RefreshToken => refresh_token(stream, token),
+            types::CAccount::PublishKey { public_key } => publish_key(stream, token, public_key),
+            types::CAccount::LookupKey { username } => lookup_key(stream, token, username),
+            types::CAccount::ChangePassword { old, new } => {
+                change_password(stream, token, old, new)
+            }
+            types::CAccount::DeleteAccount { pw_digest } => {
+                delete_account(stream, token, pw_digest)
+            }
+            types::CAccount::RequestRename { new_username } => {
+                request_rename(stream, token, new_username)
+            }
+            types::CAccount::ListRenames => list_renames(stream, token),
+            types::CAccount::ReviewRename { username, approve } => {
+                review_rename(stream, token, username, approve)
+            }
+            types::CAccount::ListSessions => list_sessions(stream, token),
+            types::CAccount::RevokeSession { id } => revoke_session(stream, token, id),
+        },
+        CPacket::SendMessage(csend_message) => match csend_message {
+            types::CSendMessage::Send { message } => send_msg(stream, token, message),
+        },
+        CPacket::RecvMessage(crecv_message) => match crecv_message {
+            types::CRecvMessage::Subscribe => subscribe(stream, token, None),
+            types::CRecvMessage::SubscribeDevice { device } => {
+                subscribe(stream, token, Some(device))
+            }
+            types::CRecvMessage::History {
+                peer_or_channel,
+                before,
+                limit,
+            } => history(stream, token, peer_or_channel, before, limit),
+            types::CRecvMessage::Read { ids } => mark_read(stream, token, ids),
+        },
+        CPacket::Channel(c_channel) => match c_channel {
+            types::CChannel::Join { channel } => join_channel(stream, token, channel),
+            types::CChannel::Part { channel } => part_channel(stream, token, channel),
+            types::CChannel::List => list_channels(stream, token),
+            types::CChannel::Topic { channel, topic } => {
+                channel_topic(stream, token, channel, topic)
+            }
+        },
+        CPacket::Presence(c_presence) => match c_presence {
+            types::CPresence::Watch { usernames } => watch_presence(stream, token, usernames),
+            types::CPresence::Unwatch { usernames } => unwatch_presence(stream, token, usernames),
+            types::CPresence::SetAway { away } => set_away(stream, token, away),
+        },
+        CPacket::Handshake { .. } | CPacket::Sequenced { .. } => Err(ServerError::Malformed),
+    }
+}
+/// Checks the sequence number of a sequenced packet and returns the request inside, along with
+/// the token it was sent with
+fn unseal(
+    stream: &PacketWriter,
+    priv_key: &RsaPrivateKey,
+    token: RsaData<u128>,
+    packet: AesData<SequencedPacket>,
+) -> Result<(u128, CPacket), ServerError> {
+    let (token, usr) = session(stream, token.get(priv_key)?)?;
+    let SequencedPacket { seq, packet } = packet.get(&usr.aes_key)?;
+    match TOKEN_MAP.write().unwrap().get_mut(&token) {
+        Some(usr) if in_sequence(usr.last_seq, seq) => usr.last_seq = seq,
+        Some(usr) => {
+            warn!("Rejected request {seq}, last accepted was {}", usr.last_seq);
+            return Err(ServerError::Replayed);
+        }
+        None => return Err(ServerError::InvalidToken),
     }
+    Ok((token, packet))
+}
+/// Whether request `seq` may follow the last accepted request `last_seq`. Numbers already seen
+/// are replays, and ones too far ahead could only come from requests captured before the
+/// session's numbering started over.
+fn in_sequence(last_seq: u64, seq: u64) -> bool {
+    seq > last_seq && seq - last_seq <= types::SEQUENCE_WINDOW
 }
 fn handshake(
-    stream: &mut AsymmetricTcpStream<SPacket, CPacket>,
+    stream: &PacketWriter,
+    peer: IpAddr,
     client_key: &RsaPublicKey,
     priv_key: &RsaPrivateKey,
-) {
-    println!("handshake");
-    let aes_key: [u8; 16] = rand::rng().random(); // 1024 bit key
-    let user = TokenData {
+    version: u32,
+    mut capabilities: Vec<String>,
+) -> Result<(), ServerError> {
+    let version = version.min(types::PROTOCOL_VERSION);
+    capabilities.retain(|cap| types::CAPABILITIES.contains(&cap.as_str()));
+    debug!("handshake, protocol version {version}, capabilities {capabilities:?}");
+    let aes_key: [u8; 16] = rand::rng().random(); // 128 bit AES-GCM key
+    let now = Instant::now();
+    let token = insert_token(TokenData {
         username: None,
         rsa_key: client_key.clone(),
         aes_key: aes_key.to_vec(),
-    };
-    let read_lock = TOKEN_MAP.read().unwrap();
-    let token = loop {
+        connection: stream.id(),
+        peer,
+        created: now,
+        last_used: now,
+        last_seq: 0,
+        away: false,
+        device: None,
+        subscribed: false,
+    });
+    let shared_key = RsaData::new(aes_key.to_vec(), client_key)?;
+    let token = RsaData::new(token, client_key)?;
+    let signature = enc::sign(
+        &HandshakeTranscript {
+            client_key,
+            version,
+            min_version: types::MIN_PROTOCOL_VERSION,
+            capabilities: &capabilities,
+            shared_key: &shared_key,
+            token: &token,
+        },
+        priv_key,
+    )?;
+    stream.send(SPacket::Handshake {
+        server_key: priv_key.into(),
+        version,
+        min_version: types::MIN_PROTOCOL_VERSION,
+        capabilities,
+        shared_key,
+        token,
+        signature,
+    })?;
+    debug!("Sent off handshake");
+    Ok(())
+}
+/// Stores `usr` under a fresh random token and returns the token
+fn insert_token(usr: TokenData) -> u128 {
+    let mut token_map = TOKEN_MAP.write().unwrap();
+    loop {
         let token: u128 = rand::rng().random();
-        if !read_lock.contains_key(&token) {
-            drop(read_lock);
-            TOKEN_MAP.write().unwrap().insert(token, user);
+        if let std::collections::hash_map::Entry::Vacant(vacant_entry) = token_map.entry(token) {
+            vacant_entry.insert(usr);
             break token;
         }
+    }
+}
+/// Returns `token` along with a snapshot of its session, provided the token belongs to this
+/// connection and has not expired
+fn session(stream: &PacketWriter, token: u128) -> Result<(u128, TokenData), ServerError> {
+    let now = Instant::now();
+    let mut token_map = TOKEN_MAP.write().unwrap();
+    let Some(usr) = token_map.get_mut(&token) else {
+        return Err(ServerError::InvalidToken);
     };
-    stream
-        .send(SPacket::Handshake {
-            server_key: priv_key.into(),
-            shared_key: RsaData::new(aes_key.to_vec(), client_key).unwrap(),
-            token: RsaData::new(token, client_key).unwrap(),
-        })
-        .unwrap();
-    println!("Sent off handshake");
+    if usr.connection != stream.id() {
+        warn!("Session token presented on a connection it was not issued to");
+        return Err(ServerError::InvalidToken);
+    }
+    if usr.is_expired(now) {
+        let usr = token_map.remove(&token).unwrap();
+        drop(token_map);
+        if let Some(username) = usr.username {
+            delivery::kick(&username, token);
+            refresh_presence(&username);
+        }
+        return Err(ServerError::InvalidToken);
+    }
+    usr.last_used = now;
+    Ok((token, usr.clone()))
+}
+/// Like [`session`], but also requires the session to be logged in
+fn logged_in(stream: &PacketWriter, token: u128) -> Result<(u128, TokenData, String), ServerError> {
+    let (token, usr) = session(stream, token)?;
+    match usr.username.clone() {
+        Some(username) => Ok((token, usr, username)),
+        None => Err(ServerError::NotLoggedIn),
+    }
 }
 fn login(
-    stream: &mut AsymmetricTcpStream<SPacket, CPacket>,
-    priv_key: &RsaPrivateKey,
-    token: RsaData<u128>,
+    stream: &PacketWriter,
+    token: u128,
     creds: AesData<Credentials>,
-) {
-    let token = token.get(priv_key).unwrap();
-    match TOKEN_MAP.write().unwrap().entry(token) {
-        std::collections::hash_map::Entry::Occupied(mut occupied_entry) => {
-            let creds = creds.get(&occupied_entry.get().aes_key).unwrap();
-
-            if Some(&creds.pw_digest) == ACCOUNT_MAP.read().unwrap().get(&creds.username) {
-                occupied_entry.get_mut().username = Some(creds.username);
-                stream
-                    .send(SPacket::Account(types::SAccount::Success))
-                    .unwrap()
-            } else {
-                stream
-                    .send(SPacket::Account(types::SAccount::IncorrectPassword))
-                    .unwrap()
-            }
+) -> Result<(), ServerError> {
+    // Password hashing is slow, so don't hold the token lock while verifying
+    let (token, usr) = session(stream, token)?;
+    let creds = creds.get(&usr.aes_key)?;
+    if !check_password(&creds.username, &creds.pw_digest)? {
+        stream.send(SPacket::Account(SAccount::IncorrectPassword))?;
+        return Ok(());
+    }
+    let previous = match TOKEN_MAP.write().unwrap().get_mut(&token) {
+        Some(usr) => {
+            usr.away = false;
+            usr.username.replace(creds.username.clone())
         }
-        std::collections::hash_map::Entry::Vacant(_) => {
-            stream
-                .send(SPacket::Account(types::SAccount::InvalidToken))
-                .unwrap();
+        None => return Err(ServerError::InvalidToken),
+    };
+    if let Some(previous) = previous.filter(|previous| *previous != creds.username) {
+        refresh_presence(&previous);
+    }
+    refresh_presence(&creds.username);
+    stream.send(SPacket::Account(SAccount::Success))?;
+    Ok(())
+}
+/// Checks `pw_digest` against the stored hash of `username`, upgrading hashes made with
+/// outdated parameters
+fn check_password(username: &str, pw_digest: &str) -> Result<bool, ServerError> {
+    let verification = match storage().get_account(username) {
+        Some(stored) => tokio::task::block_in_place(|| password::verify(pw_digest, &stored)),
+        None => password::Verification::Invalid,
+    };
+    match verification {
+        password::Verification::Invalid => Ok(false),
+        password::Verification::ValidNeedsRehash => {
+            info!("Upgrading password hash of {username}");
+            storage().set_password(
+                username,
+                &tokio::task::block_in_place(|| password::hash(pw_digest)),
+            )?;
+            Ok(true)
         }
+        password::Verification::Valid => Ok(true),
     }
 }
 fn create_account(
-    stream: &mut AsymmetricTcpStream<SPacket, CPacket>,
-    priv_key: &RsaPrivateKey,
-    token: RsaData<u128>,
+    stream: &PacketWriter,
+    token: u128,
     creds: AesData<Credentials>,
-) {
-    let token = token.get(priv_key).unwrap();
-    match TOKEN_MAP.read().unwrap().get(&token) {
-        Some(user) => {
-            let creds = creds.get(&user.aes_key).unwrap();
-            if creds.username.chars().any(|chr| !chr.is_alphanumeric()) {
-                stream
-                    .send(SPacket::Account(SAccount::InvalidUsername))
-                    .unwrap();
-                return;
-            }
+) -> Result<(), ServerError> {
+    let (_, usr) = session(stream, token)?;
+    let creds = creds.get(&usr.aes_key)?;
+    if !config().username.is_valid(&creds.username) {
+        stream.send(SPacket::Account(SAccount::InvalidUsername))?;
+        return Ok(());
+    }
+    // Checked before hashing, as it's much cheaper. Creating the account below still fails if
+    // someone else takes the name in the meantime.
+    if storage().get_account(&creds.username).is_some() || is_admin_name(&creds.username) {
+        debug!("Account already exists");
+        stream.send(SPacket::Account(types::SAccount::AccountExists))?;
+        return Ok(());
+    }
+    if !ACCOUNT_CREATIONS.admit(usr.peer) {
+        warn!(
+            "Refused account creation from {}, too many recently",
+            usr.peer
+        );
+        stream.send(SPacket::Account(SAccount::TooManyAccounts))?;
+        return Ok(());
+    }
 
-            if ACCOUNT_MAP.read().unwrap().contains_key(&creds.username) {
-                println!("Account already exists");
-                stream
-                    .send(SPacket::Account(types::SAccount::AccountExists))
-                    .unwrap();
-            } else {
-                ACCOUNT_MAP
-                    .write()
-                    .unwrap()
-                    .insert(creds.username, creds.pw_digest);
-                stream
-                    .send(SPacket::Account(types::SAccount::Success))
-                    .unwrap();
-            }
-        }
-        None => {
-            stream
-                .send(SPacket::Account(SAccount::InvalidToken))
-                .unwrap();
-        }
+    let pw_hash = tokio::task::block_in_place(|| password::hash(&creds.pw_digest));
+    if storage().create_account(&creds.username, &pw_hash)? {
+        stream.send(SPacket::Account(types::SAccount::Success))?;
+    } else {
+        debug!("Account already exists");
+        stream.send(SPacket::Account(types::SAccount::AccountExists))?;
+    }
+    Ok(())
+}
+fn change_password(
+    stream: &PacketWriter,
+    token: u128,
+    old: AesData<String>,
+    new: AesData<String>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let (old, new) = (old.get(&usr.aes_key)?, new.get(&usr.aes_key)?);
+    if !check_password(&username, &old)? {
+        stream.send(SPacket::Account(SAccount::IncorrectPassword))?;
+        return Ok(());
+    }
+    let pw_hash = tokio::task::block_in_place(|| password::hash(&new));
+    storage().set_password(&username, &pw_hash)?;
+    info!("Changed password of {username}");
+    // Whoever knew the old password may still be logged in elsewhere
+    remove_tokens(|other| {
+        other.username.as_deref() == Some(username.as_str()) && other.connection != stream.id()
+    });
+    stream.send(SPacket::Account(SAccount::Success))?;
+    Ok(())
+}
+fn delete_account(
+    stream: &PacketWriter,
+    token: u128,
+    pw_digest: AesData<String>,
+) -> Result<(), ServerError> {
+    let (token, usr, username) = logged_in(stream, token)?;
+    if !check_password(&username, &pw_digest.get(&usr.aes_key)?)? {
+        stream.send(SPacket::Account(SAccount::IncorrectPassword))?;
+        return Ok(());
+    }
+    storage().delete_account(&username)?;
+    info!("Deleted account {username}");
+    remove_tokens(|other| {
+        other.username.as_deref() == Some(username.as_str()) && other.connection != stream.id()
+    });
+    // This session stays usable, but is no longer logged in
+    if let Some(usr) = TOKEN_MAP.write().unwrap().get_mut(&token) {
+        usr.username = None;
+        usr.subscribed = false;
+    }
+    delivery::unsubscribe(&username, token);
+    refresh_presence(&username);
+    stream.send(SPacket::Account(SAccount::Success))?;
+    Ok(())
+}
+fn request_rename(
+    stream: &PacketWriter,
+    token: u128,
+    new_username: AesData<String>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let new_username = new_username.get(&usr.aes_key)?;
+    let reply = if !config().username.is_valid(&new_username) {
+        SAccount::InvalidUsername
+    } else if storage().get_account(&new_username).is_some() || is_admin_name(&new_username) {
+        SAccount::AccountExists
+    } else {
+        storage().set_rename_request(&username, Some(&new_username))?;
+        info!("{username} asked to be renamed to {new_username}");
+        SAccount::Success
+    };
+    stream.send(SPacket::Account(reply))?;
+    Ok(())
+}
+/// Admin rights go with the name, so names listed as admins can't be taken by a new account or
+/// a rename. Only an account that existed before its name was listed can be an admin.
+fn is_admin_name(username: &str) -> bool {
+    config().admins.iter().any(|admin| admin == username)
+}
+/// Like [`logged_in`], but replies with `SAccount::NotAdmin` and returns `None` unless the user
+/// is an admin
+fn admin(stream: &PacketWriter, token: u128) -> Result<Option<(TokenData, String)>, ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    if !is_admin_name(&username) {
+        stream.send(SPacket::Account(SAccount::NotAdmin))?;
+        return Ok(None);
+    }
+    Ok(Some((usr, username)))
+}
+fn list_renames(stream: &PacketWriter, token: u128) -> Result<(), ServerError> {
+    let Some((usr, _)) = admin(stream, token)? else {
+        return Ok(());
+    };
+    stream.send(SPacket::Account(SAccount::RenameRequests {
+        requests: AesData::new(storage().rename_requests(), &usr.aes_key)?,
+    }))?;
+    Ok(())
+}
+fn review_rename(
+    stream: &PacketWriter,
+    token: u128,
+    username: AesData<String>,
+    approve: bool,
+) -> Result<(), ServerError> {
+    let Some((usr, admin)) = admin(stream, token)? else {
+        return Ok(());
+    };
+    let username = username.get(&usr.aes_key)?;
+    let Some(request) = storage()
+        .rename_requests()
+        .into_iter()
+        .find(|request| request.username == username)
+    else {
+        stream.send(SPacket::Account(SAccount::NoRenameRequest))?;
+        return Ok(());
+    };
+    if !approve {
+        storage().set_rename_request(&username, None)?;
+        info!(
+            "{admin} rejected renaming {username} to {}",
+            request.new_username
+        );
+    } else if !is_admin_name(&request.new_username)
+        && storage().rename_account(&username, &request.new_username)?
+    {
+        info!("{admin} renamed {username} to {}", request.new_username);
+        // Sessions are tied to the old name, so the user logs in again under the new one
+        remove_tokens(|other| other.username.as_deref() == Some(username.as_str()));
+    } else {
+        stream.send(SPacket::Account(SAccount::AccountExists))?;
+        return Ok(());
     }
+    stream.send(SPacket::Account(SAccount::Success))?;
+    Ok(())
 }
 fn send_msg(
-    stream: &mut AsymmetricTcpStream<SPacket, CPacket>,
-    priv_key: &RsaPrivateKey,
-    token: RsaData<u128>,
+    stream: &PacketWriter,
+    token: u128,
     message: AesData<OutboundMessage>,
-) {
-    let token = token.get(priv_key).unwrap();
-    let Some(usr) = TOKEN_MAP.read().unwrap().get(&token).cloned() else {
-        stream
-            .send(SPacket::Account(SAccount::InvalidToken))
-            .unwrap();
-        return;
-    };
-    if let Some(username) = usr.username {
-        let message = message.get(&usr.aes_key).unwrap();
-        for recipient in &message.recipients {
-            match MESSAGE_MAP.write().unwrap().entry(recipient.to_string()) {
-                std::collections::hash_map::Entry::Occupied(mut occupied_entry) => {
-                    occupied_entry.get_mut().push_back(InboundMessage {
-                        sender: username.clone(),
-                        recipients: message.recipients.clone(),
-                        contents: message.contents.clone(),
-                    });
-                }
-                std::collections::hash_map::Entry::Vacant(vacant_entry) => {
-                    vacant_entry.insert(
-                        vec![InboundMessage {
-                            sender: username.clone(),
-                            recipients: message.recipients.clone(),
-                            contents: message.contents.clone(),
-                        }]
-                        .into(),
-                    );
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let message = message.get(&usr.aes_key)?;
+    // The server can't read sealed boxes, so it can't fan them out to channel members
+    if let MessageBody::Sealed(_) = &message.contents {
+        if let Some(channel) = message
+            .recipients
+            .iter()
+            .find(|recipient| types::is_channel_name(recipient))
+        {
+            stream.send(SPacket::SendMessage(SSendMessage::SealedToChannel {
+                channel: channel.clone(),
+            }))?;
+            return Ok(());
+        }
+    }
+    // Each user receives a message once, through the first recipient entry that reaches them
+    let mut deliveries: Vec<(String, Option<String>)> = Vec::new();
+    let mut seen = HashSet::new();
+    for recipient in &message.recipients {
+        if types::is_channel_name(recipient) {
+            let members = storage()
+                .get_channel(recipient)
+                .map(|chan| chan.members)
+                .unwrap_or_default();
+            if !members.contains(&username) {
+                stream.send(SPacket::SendMessage(SSendMessage::NotInChannel {
+                    channel: recipient.clone(),
+                }))?;
+                return Ok(());
+            }
+            for member in members {
+                if member != username && seen.insert(member.clone()) {
+                    deliveries.push((member, Some(recipient.clone())));
                 }
             }
+        } else if seen.insert(recipient.clone()) {
+            deliveries.push((recipient.clone(), None));
+        }
+    }
+    // Direct recipients without an account are reported rather than queued for, so a typo
+    // doesn't grow a queue nobody will ever collect
+    let mut statuses = BTreeMap::new();
+    deliveries.retain(|(recipient, channel)| {
+        if channel.is_some() {
+            return true;
+        }
+        let known = storage().get_account(recipient).is_some();
+        let status = if known {
+            DeliveryStatus::Queued
+        } else {
+            DeliveryStatus::UnknownUser
+        };
+        statuses.insert(recipient.clone(), status);
+        if !known {
+            debug!("Not delivering message from {username} to unknown user {recipient}");
+        }
+        known
+    });
+    // Unknown users can't have published a key, so only existing recipients need a box
+    if let MessageBody::Sealed(boxes) = &message.contents {
+        if let Some((recipient, _)) = deliveries
+            .iter()
+            .find(|(recipient, _)| !boxes.contains_key(recipient))
+        {
+            stream.send(SPacket::SendMessage(SSendMessage::MissingSealedBox {
+                recipient: recipient.clone(),
+            }))?;
+            return Ok(());
+        }
+    }
+    if let Some((recipient, _)) = deliveries
+        .iter()
+        .find(|(recipient, _)| storage().queued_messages(recipient) >= config().max_queued_messages)
+    {
+        stream.send(SPacket::SendMessage(SSendMessage::QueueFull {
+            recipient: recipient.clone(),
+        }))?;
+        return Ok(());
+    }
+    let id = storage().next_message_id()?;
+    let timestamp = unix_millis();
+    let retain = config().history_per_conversation;
+    for (recipient, channel) in deliveries {
+        let inbound = InboundMessage {
+            id,
+            timestamp,
+            sender: username.clone(),
+            recipients: message.recipients.clone(),
+            contents: body_for(&message.contents, &recipient),
+            channel: channel.clone(),
+        };
+        if retain > 0 {
+            let conversation = channel.as_deref().unwrap_or(&username);
+            storage().push_history(&recipient, conversation, inbound.clone(), retain)?;
+        }
+        storage().push_message(&recipient, inbound, config().max_queued_messages)?;
+        delivery::notify(&recipient);
+    }
+    // The sender's own copy, so their side of each conversation shows up in history too
+    if retain > 0 {
+        let conversations: BTreeSet<_> = message.recipients.iter().collect();
+        for conversation in conversations {
+            if *conversation == username
+                || statuses.get(conversation) == Some(&DeliveryStatus::UnknownUser)
+            {
+                continue;
+            }
+            let inbound = InboundMessage {
+                id,
+                timestamp,
+                sender: username.clone(),
+                recipients: message.recipients.clone(),
+                contents: body_for(&message.contents, &username),
+                channel: types::is_channel_name(conversation).then(|| conversation.clone()),
+            };
+            storage().push_history(&username, conversation, inbound, retain)?;
         }
-        stream
-            .send(SPacket::SendMessage(SSendMessage::Success))
-            .unwrap()
     }
+    receipts::track(
+        id,
+        &username,
+        statuses
+            .iter()
+            .filter(|(_, status)| **status == DeliveryStatus::Queued)
+            .map(|(recipient, _)| recipient.clone()),
+    );
+    stream.send(SPacket::SendMessage(SSendMessage::Success { id, statuses }))?;
+    Ok(())
 }
-fn recv_msg(
-    stream: &mut AsymmetricTcpStream<SPacket, CPacket>,
-    priv_key: &RsaPrivateKey,
-    token: RsaData<u128>,
-) {
-    let token = token.get(priv_key).unwrap();
-    let Some(usr) = TOKEN_MAP.read().unwrap().get(&token).cloned() else {
-        stream
-            .send(SPacket::Account(SAccount::InvalidToken))
-            .unwrap();
-        return;
+/// The part of a message body `username` is allowed to see, just their own box if sealed
+fn body_for(contents: &MessageBody, username: &str) -> MessageBody {
+    match contents {
+        MessageBody::Plain(contents) => MessageBody::Plain(contents.clone()),
+        MessageBody::Sealed(boxes) => MessageBody::Sealed(
+            boxes
+                .get_key_value(username)
+                .map(|(username, sealed)| (username.clone(), sealed.clone()))
+                .into_iter()
+                .collect(),
+        ),
+    }
+}
+fn history(
+    stream: &PacketWriter,
+    token: u128,
+    peer_or_channel: AesData<String>,
+    before: Option<u64>,
+    limit: u32,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let conversation = peer_or_channel.get(&usr.aes_key)?;
+    let limit = (limit as usize).min(HISTORY_PAGE_LIMIT);
+    let messages = storage().history(&username, &conversation, before, limit);
+    stream.send(SPacket::RecvMessage(SRecvMessage::History {
+        messages: AesData::new(messages, &usr.aes_key)?,
+    }))?;
+    Ok(())
+}
+fn mark_read(
+    stream: &PacketWriter,
+    token: u128,
+    ids: AesData<Vec<u64>>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    for id in ids.get(&usr.aes_key)? {
+        receipts::read(id, &username);
+    }
+    stream.send(SPacket::RecvMessage(SRecvMessage::Acknowledged))?;
+    Ok(())
+}
+fn publish_key(
+    stream: &PacketWriter,
+    token: u128,
+    public_key: [u8; 32],
+) -> Result<(), ServerError> {
+    let (_, _, username) = logged_in(stream, token)?;
+    storage().set_public_key(&username, public_key)?;
+    stream.send(SPacket::Account(SAccount::Success))?;
+    Ok(())
+}
+fn lookup_key(
+    stream: &PacketWriter,
+    token: u128,
+    username: AesData<String>,
+) -> Result<(), ServerError> {
+    let (_, usr, _) = logged_in(stream, token)?;
+    let username = username.get(&usr.aes_key)?;
+    stream.send(SPacket::Account(SAccount::PublicKey {
+        public_key: storage().get_public_key(&username),
+    }))?;
+    Ok(())
+}
+fn subscribe(
+    stream: &Arc<PacketWriter>,
+    token: u128,
+    device: Option<AesData<Device>>,
+) -> Result<(), ServerError> {
+    let (token, usr, username) = logged_in(stream, token)?;
+    let device = device.map(|device| device.get(&usr.aes_key)).transpose()?;
+    let device_id = device.as_ref().map_or(LEGACY_DEVICE, |device| device.id);
+    match TOKEN_MAP.write().unwrap().get_mut(&token) {
+        Some(usr) => {
+            usr.device = device;
+            usr.subscribed = true;
+        }
+        None => return Err(ServerError::InvalidToken),
+    }
+    // Confirm before the delivery task can start pushing messages
+    stream.send(SPacket::RecvMessage(SRecvMessage::Subscribed))?;
+    delivery::subscribe(username, token, device_id, stream.clone(), usr.aes_key);
+    Ok(())
+}
+fn list_sessions(stream: &PacketWriter, token: u128) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let now = Instant::now();
+    let mut sessions: Vec<SessionInfo> = TOKEN_MAP
+        .read()
+        .unwrap()
+        .values()
+        .filter(|other| other.username.as_deref() == Some(username.as_str()))
+        .map(|other| SessionInfo {
+            id: other.connection,
+            device: other.device.clone(),
+            current: other.connection == stream.id(),
+            age_secs: now.duration_since(other.created).as_secs(),
+            idle_secs: now.duration_since(other.last_used).as_secs(),
+        })
+        .collect();
+    sessions.sort_by_key(|session| session.id);
+    stream.send(SPacket::Account(SAccount::Sessions {
+        sessions: AesData::new(sessions, &usr.aes_key)?,
+    }))?;
+    Ok(())
+}
+fn revoke_session(stream: &PacketWriter, token: u128, id: u64) -> Result<(), ServerError> {
+    let (_, _, username) = logged_in(stream, token)?;
+    let is_target = |other: &TokenData| {
+        other.connection == id
+            && other.connection != stream.id()
+            && other.username.as_deref() == Some(username.as_str())
+    };
+    let device = TOKEN_MAP
+        .read()
+        .unwrap()
+        .values()
+        .find(|other| is_target(other))
+        .map(|other| other.device.as_ref().map(|device| device.id));
+    let Some(device) = device else {
+        stream.send(SPacket::Account(SAccount::NoSuchSession))?;
+        return Ok(());
+    };
+    remove_tokens(is_target);
+    info!("{username} revoked session {id}");
+    // Unless the device is still logged in elsewhere, it starts over if it comes back
+    if let Some(device) = device {
+        let in_use = TOKEN_MAP.read().unwrap().values().any(|other| {
+            other.username.as_deref() == Some(username.as_str())
+                && other
+                    .device
+                    .as_ref()
+                    .is_some_and(|other| other.id == device)
+        });
+        if !in_use {
+            storage().forget_device(&username, device)?;
+        }
+    }
+    stream.send(SPacket::Account(SAccount::Success))?;
+    Ok(())
+}
+fn logout(stream: &PacketWriter, token: u128) -> Result<(), ServerError> {
+    let (token, _) = session(stream, token)?;
+    let Some(usr) = TOKEN_MAP.write().unwrap().remove(&token) else {
+        return Err(ServerError::InvalidToken);
     };
     if let Some(username) = usr.username {
-        loop {
-            if let Some(vec) = MESSAGE_MAP.read().unwrap().get(&username) {
-                if !vec.is_empty() {
-                    break;
-                }
+        delivery::unsubscribe(&username, token);
+        refresh_presence(&username);
+    }
+    stream.send(SPacket::Account(types::SAccount::Success))?;
+    Ok(())
+}
+fn refresh_token(stream: &PacketWriter, token: u128) -> Result<(), ServerError> {
+    let (old_token, _) = session(stream, token)?;
+    let Some(mut usr) = TOKEN_MAP.write().unwrap().remove(&old_token) else {
+        return Err(ServerError::InvalidToken);
+    };
+    usr.last_used = Instant::now();
+    let rsa_key = usr.rsa_key.clone();
+    let username = usr.username.clone();
+    let new_token = insert_token(usr);
+    if let Some(username) = username {
+        delivery::rekey(&username, old_token, new_token);
+    }
+    stream.send(SPacket::Account(SAccount::Token {
+        token: RsaData::new(new_token, &rsa_key)?,
+    }))?;
+    Ok(())
+}
+fn watch_presence(
+    stream: &PacketWriter,
+    token: u128,
+    usernames: AesData<Vec<String>>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let mut usernames = usernames.get(&usr.aes_key)?;
+    usernames.retain(|watched| storage().get_account(watched).is_some());
+    let watching = presence::watch(&username, usernames);
+    stream.send(SPacket::Presence(SPresence::Watching {
+        presence: AesData::new(watching, &usr.aes_key)?,
+    }))?;
+    Ok(())
+}
+fn unwatch_presence(
+    stream: &PacketWriter,
+    token: u128,
+    usernames: AesData<Vec<String>>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    presence::unwatch(&username, usernames.get(&usr.aes_key)?);
+    stream.send(SPacket::Presence(SPresence::Success))?;
+    Ok(())
+}
+fn set_away(stream: &PacketWriter, token: u128, away: bool) -> Result<(), ServerError> {
+    let (token, _, username) = logged_in(stream, token)?;
+    match TOKEN_MAP.write().unwrap().get_mut(&token) {
+        Some(usr) => usr.away = away,
+        None => return Err(ServerError::InvalidToken),
+    }
+    refresh_presence(&username);
+    stream.send(SPacket::Presence(SPresence::Success))?;
+    Ok(())
+}
+fn join_channel(
+    stream: &PacketWriter,
+    token: u128,
+    channel: AesData<String>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let channel = channel.get(&usr.aes_key)?;
+    if !types::is_channel_name(&channel) {
+        stream.send(SPacket::Channel(SChannel::InvalidChannel))?;
+    } else if storage().join_channel(&channel, &username)? {
+        stream.send(SPacket::Channel(SChannel::Success))?;
+    } else {
+        stream.send(SPacket::Channel(SChannel::AlreadyInChannel))?;
+    }
+    Ok(())
+}
+fn part_channel(
+    stream: &PacketWriter,
+    token: u128,
+    channel: AesData<String>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let channel = channel.get(&usr.aes_key)?;
+    if storage().part_channel(&channel, &username)? {
+        stream.send(SPacket::Channel(SChannel::Success))?;
+    } else {
+        stream.send(SPacket::Channel(SChannel::NotInChannel))?;
+    }
+    Ok(())
+}
+fn list_channels(stream: &PacketWriter, token: u128) -> Result<(), ServerError> {
+    let (_, usr, _) = logged_in(stream, token)?;
+    stream.send(SPacket::Channel(SChannel::List {
+        channels: AesData::new(storage().list_channels(), &usr.aes_key)?,
+    }))?;
+    Ok(())
+}
+fn channel_topic(
+    stream: &PacketWriter,
+    token: u128,
+    channel: AesData<String>,
+    topic: AesData<Option<String>>,
+) -> Result<(), ServerError> {
+    let (_, usr, username) = logged_in(stream, token)?;
+    let channel = channel.get(&usr.aes_key)?;
+    let Some(info) = storage().get_channel(&channel) else {
+        stream.send(SPacket::Channel(SChannel::InvalidChannel))?;
+        return Ok(());
+    };
+    let topic = match topic.get(&usr.aes_key)? {
+        Some(new_topic) => {
+            if !info.members.contains(&username) {
+                stream.send(SPacket::Channel(SChannel::NotInChannel))?;
+                return Ok(());
             }
-            std::thread::sleep(Duration::from_millis(250))
-        }
-        let next_msg = MESSAGE_MAP
-            .write()
-            .unwrap()
-            .get_mut(&username)
-            .unwrap()
-            .pop_front();
-        stream
-            .send(SPacket::RecvMessage(types::SRecvMessage::NextMsg {
-                message: AesData::new(next_msg.unwrap(), &usr.aes_key).unwrap(),
-            }))
-            .unwrap();
-    }
-}
-fn logout(
-    stream: &mut AsymmetricTcpStream<SPacket, CPacket>,
-    priv_key: &RsaPrivateKey,
-    token: RsaData<u128>,
-) {
-    let token = token.get(priv_key).unwrap();
-    match TOKEN_MAP.write().unwrap().entry(token) {
-        std::collections::hash_map::Entry::Occupied(occupied_entry) => {
-            occupied_entry.remove();
-            stream
-                .send(SPacket::Account(types::SAccount::Success))
-                .unwrap()
-        }
-        std::collections::hash_map::Entry::Vacant(_) => {
-            stream
-                .send(SPacket::Account(types::SAccount::InvalidToken))
-                .unwrap();
+            storage().set_topic(&channel, Some(new_topic.clone()))?;
+            Some(new_topic)
         }
+        None => info.topic,
+    };
+    stream.send(SPacket::Channel(SChannel::Topic {
+        topic: AesData::new(topic, &usr.aes_key)?,
+    }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_numbers_must_go_up() {
+        assert!(in_sequence(0, 1));
+        assert!(in_sequence(5, 6));
+        assert!(!in_sequence(5, 5));
+        assert!(!in_sequence(5, 4));
+        assert!(!in_sequence(0, 0));
+    }
+
+    #[test]
+    fn sequence_numbers_may_skip_within_the_window() {
+        assert!(in_sequence(10, 10 + types::SEQUENCE_WINDOW));
+        assert!(!in_sequence(10, 11 + types::SEQUENCE_WINDOW));
+        assert!(!in_sequence(0, u64::MAX));
     }
 }
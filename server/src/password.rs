@@ -0,0 +1,91 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::convert::TryFrom;
+use subtle::ConstantTimeEq;
+
+pub enum Verification {
+    Valid,
+    /// The password matched a hash in an outdated format, it should be re-hashed and stored
+    ValidNeedsRehash,
+    Invalid,
+}
+
+/// Hashes the client supplied password digest with Argon2id under a fresh random salt, returning
+/// a PHC string which embeds the salt and parameters
+pub fn hash(pw_digest: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(pw_digest.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub fn verify(pw_digest: &str, stored: &str) -> Verification {
+    match PasswordHash::new(stored) {
+        Ok(parsed) => {
+            if Argon2::default()
+                .verify_password(pw_digest.as_bytes(), &parsed)
+                .is_err()
+            {
+                Verification::Invalid
+            } else if !is_current(&parsed) {
+                Verification::ValidNeedsRehash
+            } else {
+                Verification::Valid
+            }
+        }
+        // Accounts created before hashing was added store the bare digest
+        Err(_) => {
+            if bool::from(pw_digest.as_bytes().ct_eq(stored.as_bytes())) {
+                Verification::ValidNeedsRehash
+            } else {
+                Verification::Invalid
+            }
+        }
+    }
+}
+/// Whether `parsed` was made with the algorithm, version and cost parameters [`hash`] uses now
+fn is_current(parsed: &PasswordHash) -> bool {
+    let current = Params::default();
+    parsed.algorithm == Algorithm::Argon2id.ident()
+        && parsed.version == Some(Version::default().into())
+        && Params::try_from(parsed).is_ok_and(|params| {
+            params.m_cost() == current.m_cost()
+                && params.t_cost() == current.t_cost()
+                && params.p_cost() == current.p_cost()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outdated_hashes_need_rehashing() {
+        assert!(matches!(
+            verify("digest", &hash("digest")),
+            Verification::Valid
+        ));
+        assert!(matches!(
+            verify("other", &hash("digest")),
+            Verification::Invalid
+        ));
+        assert!(matches!(
+            verify("digest", "digest"),
+            Verification::ValidNeedsRehash
+        ));
+
+        let salt = SaltString::generate(&mut OsRng);
+        let cheap = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let stored = Argon2::new(Algorithm::Argon2id, Version::default(), cheap)
+            .hash_password(b"digest", &salt)
+            .unwrap()
+            .to_string();
+        assert!(matches!(
+            verify("digest", &stored),
+            Verification::ValidNeedsRehash
+        ));
+    }
+}
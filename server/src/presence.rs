@@ -0,0 +1,132 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{LazyLock, Mutex},
+};
+use types::{Presence, PresenceStatus, SRecvMessage};
+
+use crate::delivery;
+
+/// Users one user can watch at once, further watches are ignored
+const MAX_WATCHED: usize = 1000;
+
+#[derive(Default)]
+struct State {
+    /// Last presence sent out for each user, users missing here have not been seen
+    statuses: HashMap<String, PresenceStatus>,
+    /// Who watches each user
+    watchers: HashMap<String, HashSet<String>>,
+    /// Who each user watches, so their watches can be dropped once they go offline
+    watching: HashMap<String, HashSet<String>>,
+    /// Changes that arrived while the watcher had no subscribed session, at most one per
+    /// watched user
+    pending: HashMap<String, VecDeque<Presence>>,
+}
+impl State {
+    fn status(&self, username: &str) -> PresenceStatus {
+        self.statuses
+            .get(username)
+            .copied()
+            .unwrap_or(PresenceStatus::Offline { last_seen: None })
+    }
+    fn unwatch(&mut self, watcher: &str, username: &str) {
+        if let Some(watchers) = self.watchers.get_mut(username) {
+            watchers.remove(watcher);
+            if watchers.is_empty() {
+                self.watchers.remove(username);
+            }
+        }
+    }
+}
+
+static STATE: LazyLock<Mutex<State>> = LazyLock::new(|| State::default().into());
+
+/// Records the presence of `username`, queueing it for their watchers if it changed. Going
+/// offline again keeps the time the user was first seen offline, and drops their own watches.
+pub fn update(username: &str, status: PresenceStatus) {
+    let watchers: Vec<String> = {
+        let mut state = STATE.lock().unwrap();
+        let changed = match (state.status(username), status) {
+            (PresenceStatus::Offline { .. }, PresenceStatus::Offline { .. }) => false,
+            (old, new) => old != new,
+        };
+        if !changed {
+            return;
+        }
+        state.statuses.insert(username.to_string(), status);
+        if let PresenceStatus::Offline { .. } = status {
+            for watched in state.watching.remove(username).unwrap_or_default() {
+                state.unwatch(username, &watched);
+            }
+            state.pending.remove(username);
+        }
+        state
+            .watchers
+            .get(username)
+            .map(|watchers| watchers.iter().cloned().collect())
+            .unwrap_or_default()
+    };
+    let presence = Presence {
+        username: username.to_string(),
+        status,
+    };
+    for watcher in watchers {
+        let packet = |presence| SRecvMessage::Presence { presence };
+        if delivery::broadcast(&watcher, presence.clone(), packet).is_none() {
+            continue;
+        }
+        let mut state = STATE.lock().unwrap();
+        let pending = state.pending.entry(watcher.clone()).or_default();
+        // Only the latest change matters to a watcher that hasn't collected the last one
+        match pending
+            .iter_mut()
+            .find(|queued| queued.username == username)
+        {
+            Some(queued) => queued.status = status,
+            None => pending.push_back(presence.clone()),
+        }
+        drop(state);
+        // In case a session subscribed since the broadcast
+        delivery::notify(&watcher);
+    }
+}
+/// Starts telling `watcher` about presence changes of `usernames`, and returns their current
+/// presence
+pub fn watch(watcher: &str, usernames: Vec<String>) -> Vec<Presence> {
+    let mut state = STATE.lock().unwrap();
+    let mut presence = Vec::new();
+    for username in usernames {
+        let watching = state.watching.entry(watcher.to_string()).or_default();
+        if !watching.contains(&username) && watching.len() >= MAX_WATCHED {
+            continue;
+        }
+        watching.insert(username.clone());
+        state
+            .watchers
+            .entry(username.clone())
+            .or_default()
+            .insert(watcher.to_string());
+        presence.push(Presence {
+            status: state.status(&username),
+            username,
+        });
+    }
+    presence
+}
+pub fn unwatch(watcher: &str, usernames: Vec<String>) {
+    let mut state = STATE.lock().unwrap();
+    for username in usernames {
+        if let Some(watching) = state.watching.get_mut(watcher) {
+            watching.remove(&username);
+        }
+        state.unwatch(watcher, &username);
+    }
+}
+pub fn pop(username: &str) -> Option<Presence> {
+    let mut state = STATE.lock().unwrap();
+    let pending = state.pending.get_mut(username)?;
+    let presence = pending.pop_front();
+    if pending.is_empty() {
+        state.pending.remove(username);
+    }
+    presence
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Admits at most `max` events per key within any `window`, or any number if `max` is 0
+pub struct RateLimiter<K> {
+    max: usize,
+    window: Duration,
+    events: Mutex<HashMap<K, VecDeque<Instant>>>,
+}
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Records an event for `key` and returns `true`, or returns `false` without recording it
+    /// if `key` already used up its events in the current window
+    pub fn admit(&self, key: K) -> bool {
+        if self.max == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut events = self.events.lock().unwrap();
+        // Keys whose events all fell out of the window are forgotten so the map doesn't grow
+        events.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) >= self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = events.entry(key).or_default();
+        if times.len() >= self.max {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_limited_separately() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(3600));
+        assert!(limiter.admit("a"));
+        assert!(limiter.admit("a"));
+        assert!(!limiter.admit("a"));
+        assert!(limiter.admit("b"));
+    }
+
+    #[test]
+    fn events_leave_the_window() {
+        let limiter = RateLimiter::new(1, Duration::ZERO);
+        assert!(limiter.admit("a"));
+        assert!(limiter.admit("a"));
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let limiter = RateLimiter::new(0, Duration::from_secs(3600));
+        assert!((0..100).all(|_| limiter.admit("a")));
+    }
+}
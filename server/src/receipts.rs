@@ -0,0 +1,109 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{LazyLock, Mutex},
+};
+use types::{DeliveryStatus, Receipt, SRecvMessage};
+
+use crate::{config, delivery};
+
+/// Direct messages tracked at once, the oldest are forgotten beyond this and no longer
+/// produce receipts
+const MAX_TRACKED: usize = 100_000;
+
+struct Tracked {
+    sender: String,
+    /// Recipients none of whose devices have been sent the message yet
+    undelivered: HashSet<String>,
+    unread: HashSet<String>,
+}
+
+/// Direct messages by ID whose recipients have not all read them yet. Kept in memory only, so
+/// receipts for messages sent before a restart are not sent.
+static TRACKED: LazyLock<Mutex<BTreeMap<u64, Tracked>>> = LazyLock::new(|| BTreeMap::new().into());
+/// Receipts that arrived while the sender had no subscribed session, keyed by sender
+static PENDING: LazyLock<Mutex<HashMap<String, VecDeque<Receipt>>>> =
+    LazyLock::new(|| HashMap::new().into());
+
+/// Starts tracking a message sent to `recipients` so their sender hears when it is read
+pub fn track(id: u64, sender: &str, recipients: impl IntoIterator<Item = String>) {
+    let unread: HashSet<_> = recipients.into_iter().collect();
+    if unread.is_empty() {
+        return;
+    }
+    let mut tracked = TRACKED.lock().unwrap();
+    tracked.insert(
+        id,
+        Tracked {
+            sender: sender.to_string(),
+            undelivered: unread.clone(),
+            unread,
+        },
+    );
+    while tracked.len() > MAX_TRACKED {
+        tracked.pop_first();
+    }
+}
+/// Only the first device of `recipient` to be sent message `id` produces a receipt
+pub fn delivered(id: u64, recipient: &str) {
+    let sender = {
+        let mut tracked = TRACKED.lock().unwrap();
+        let Some(message) = tracked.get_mut(&id) else {
+            return;
+        };
+        if !message.undelivered.remove(recipient) {
+            return;
+        }
+        message.sender.clone()
+    };
+    queue(&sender, id, recipient, DeliveryStatus::Delivered);
+}
+/// Ignored unless `reader` was sent message `id` and has not read it yet
+pub fn read(id: u64, reader: &str) {
+    let sender = {
+        let mut tracked = TRACKED.lock().unwrap();
+        let Some(message) = tracked.get_mut(&id) else {
+            return;
+        };
+        if !message.unread.remove(reader) {
+            return;
+        }
+        message.undelivered.remove(reader);
+        let sender = message.sender.clone();
+        if message.unread.is_empty() {
+            tracked.remove(&id);
+        }
+        sender
+    };
+    queue(&sender, id, reader, DeliveryStatus::Read);
+}
+pub fn pop(username: &str) -> Option<Receipt> {
+    let mut pending = PENDING.lock().unwrap();
+    let receipts = pending.get_mut(username)?;
+    let receipt = receipts.pop_front();
+    if receipts.is_empty() {
+        pending.remove(username);
+    }
+    receipt
+}
+fn queue(sender: &str, id: u64, recipient: &str, status: DeliveryStatus) {
+    let receipt = Receipt {
+        id,
+        recipient: recipient.to_string(),
+        status,
+    };
+    let packet = |receipt| SRecvMessage::Receipt { receipt };
+    let Some(receipt) = delivery::broadcast(sender, receipt, packet) else {
+        return;
+    };
+    {
+        let mut pending = PENDING.lock().unwrap();
+        let receipts = pending.entry(sender.to_string()).or_default();
+        receipts.push_back(receipt);
+        // Nobody is collecting these, drop the oldest rather than grow forever
+        while receipts.len() > config().max_queued_messages {
+            receipts.pop_front();
+        }
+    }
+    // In case a session subscribed since the broadcast
+    delivery::notify(sender);
+}
@@ -0,0 +1,1158 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+};
+use types::{ChannelInfo, HistoryEntry, InboundMessage, RenameRequest};
+
+pub trait Storage: Send + Sync {
+    /// Returns the stored password hash of the account
+    fn get_account(&self, username: &str) -> Option<String>;
+    /// Returns `false` without modifying anything if the account already exists.
+    fn create_account(&self, username: &str, pw_hash: &str) -> io::Result<bool>;
+    fn set_password(&self, username: &str, pw_hash: &str) -> io::Result<()>;
+    /// Removes the account along with its key, queued messages, history, channel memberships
+    /// and rename request
+    fn delete_account(&self, username: &str) -> io::Result<()>;
+    /// Moves everything stored for the account over to `new_username`. Returns `false` without
+    /// modifying anything if `new_username` is taken or `username` does not exist.
+    fn rename_account(&self, username: &str, new_username: &str) -> io::Result<bool>;
+    /// Replaces the pending request to rename `username`, `None` withdraws it
+    fn set_rename_request(&self, username: &str, new_username: Option<&str>) -> io::Result<()>;
+    /// Pending rename requests, oldest first
+    fn rename_requests(&self) -> Vec<RenameRequest>;
+    fn set_public_key(&self, username: &str, public_key: [u8; 32]) -> io::Result<()>;
+    fn get_public_key(&self, username: &str) -> Option<[u8; 32]>;
+    /// Queues `message` for every device of `recipient`, dropping the oldest queued messages
+    /// beyond `retain`
+    fn push_message(
+        &self,
+        recipient: &str,
+        message: InboundMessage,
+        retain: usize,
+    ) -> io::Result<()>;
+    /// Returns the next message `device` has not been sent and moves its cursor past it. A
+    /// device seen for the first time starts at the oldest message still queued.
+    fn next_message(&self, recipient: &str, device: u128) -> io::Result<Option<InboundMessage>>;
+    /// Messages not yet sent to even the most up to date device of `recipient`
+    fn queued_messages(&self, recipient: &str) -> usize;
+    /// Drops the cursor of `device`, which starts over from the oldest queued message if it
+    /// subscribes again
+    fn forget_device(&self, username: &str, device: u128) -> io::Result<()>;
+    /// Creates the channel if it does not exist yet, returns `false` if already a member.
+    fn join_channel(&self, channel: &str, username: &str) -> io::Result<bool>;
+    /// Removes the channel once its last member leaves, returns `false` if not a member.
+    fn part_channel(&self, channel: &str, username: &str) -> io::Result<bool>;
+    fn set_topic(&self, channel: &str, topic: Option<String>) -> io::Result<()>;
+    fn get_channel(&self, channel: &str) -> Option<ChannelInfo>;
+    fn list_channels(&self) -> Vec<ChannelInfo>;
+    /// Allocates an ID for a new message, never handed out before even if the message it went
+    /// to was never stored
+    fn next_message_id(&self) -> io::Result<u64>;
+    /// Records a message in the history `username` has with a user or channel, dropping the
+    /// oldest entries of that conversation beyond `retain`
+    fn push_history(
+        &self,
+        username: &str,
+        conversation: &str,
+        message: InboundMessage,
+        retain: usize,
+    ) -> io::Result<()>;
+    /// Returns up to `limit` of the newest entries older than `before`, oldest first
+    fn history(
+        &self,
+        username: &str,
+        conversation: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<HistoryEntry>;
+}
+
+#[derive(Default, Clone)]
+struct Channel {
+    topic: Option<String>,
+    members: BTreeSet<String>,
+}
+impl Channel {
+    fn info(&self, name: &str) -> ChannelInfo {
+        ChannelInfo {
+            name: name.to_string(),
+            topic: self.topic.clone(),
+            members: self.members.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Messages queued for a user. The newest are kept even once sent, so a device seen for the
+/// first time can catch up on them.
+#[derive(Default)]
+struct Mailbox {
+    /// Offset of the front of `messages`, counting every message ever queued
+    first: u64,
+    messages: VecDeque<InboundMessage>,
+    /// Offset of the next message to send each device
+    cursors: HashMap<u128, u64>,
+}
+impl Mailbox {
+    fn end(&self) -> u64 {
+        self.first + self.messages.len() as u64
+    }
+    fn pop_front(&mut self) {
+        if self.messages.pop_front().is_some() {
+            self.first += 1;
+        }
+    }
+    /// Counts offsets from the front of `messages` again, as they are once the compacted log
+    /// is replayed
+    fn rebase(&mut self) {
+        for offset in self.cursors.values_mut() {
+            *offset = offset.saturating_sub(self.first);
+        }
+        self.first = 0;
+    }
+}
+
+#[derive(Default)]
+struct History {
+    next_index: u64,
+    conversations: HashMap<String, VecDeque<HistoryEntry>>,
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    accounts: RwLock<HashMap<String, String>>,
+    public_keys: RwLock<HashMap<String, [u8; 32]>>,
+    messages: RwLock<HashMap<String, Mailbox>>,
+    channels: RwLock<HashMap<String, Channel>>,
+    history: RwLock<HashMap<String, History>>,
+    rename_requests: RwLock<Vec<RenameRequest>>,
+    next_message_id: AtomicU64,
+}
+impl Storage for MemoryStorage {
+    fn get_account(&self, username: &str) -> Option<String> {
+        self.accounts.read().unwrap().get(username).cloned()
+    }
+    fn create_account(&self, username: &str, pw_hash: &str) -> io::Result<bool> {
+        match self.accounts.write().unwrap().entry(username.to_string()) {
+            std::collections::hash_map::Entry::Occupied(_) => Ok(false),
+            std::collections::hash_map::Entry::Vacant(vacant_entry) => {
+                vacant_entry.insert(pw_hash.to_string());
+                Ok(true)
+            }
+        }
+    }
+    fn set_password(&self, username: &str, pw_hash: &str) -> io::Result<()> {
+        if let Some(stored) = self.accounts.write().unwrap().get_mut(username) {
+            *stored = pw_hash.to_string();
+        }
+        Ok(())
+    }
+    fn delete_account(&self, username: &str) -> io::Result<()> {
+        self.accounts.write().unwrap().remove(username);
+        self.public_keys.write().unwrap().remove(username);
+        self.messages.write().unwrap().remove(username);
+        self.history.write().unwrap().remove(username);
+        self.channels.write().unwrap().retain(|_, chan| {
+            chan.members.remove(username);
+            !chan.members.is_empty()
+        });
+        self.set_rename_request(username, None)
+    }
+    fn rename_account(&self, username: &str, new_username: &str) -> io::Result<bool> {
+        {
+            let mut accounts = self.accounts.write().unwrap();
+            if accounts.contains_key(new_username) {
+                return Ok(false);
+            }
+            let Some(pw_hash) = accounts.remove(username) else {
+                return Ok(false);
+            };
+            accounts.insert(new_username.to_string(), pw_hash);
+        }
+        rename_key(
+            &mut self.public_keys.write().unwrap(),
+            username,
+            new_username,
+        );
+        rename_key(&mut self.messages.write().unwrap(), username, new_username);
+        let mut history = self.history.write().unwrap();
+        rename_key(&mut history, username, new_username);
+        // Direct conversations others had with the account follow it to the new name
+        for history in history.values_mut() {
+            rename_key(&mut history.conversations, username, new_username);
+        }
+        drop(history);
+        for chan in self.channels.write().unwrap().values_mut() {
+            if chan.members.remove(username) {
+                chan.members.insert(new_username.to_string());
+            }
+        }
+        self.set_rename_request(username, None)?;
+        Ok(true)
+    }
+    fn set_rename_request(&self, username: &str, new_username: Option<&str>) -> io::Result<()> {
+        let mut requests = self.rename_requests.write().unwrap();
+        requests.retain(|request| request.username != username);
+        if let Some(new_username) = new_username {
+            requests.push(RenameRequest {
+                username: username.to_string(),
+                new_username: new_username.to_string(),
+            });
+        }
+        Ok(())
+    }
+    fn rename_requests(&self) -> Vec<RenameRequest> {
+        self.rename_requests.read().unwrap().clone()
+    }
+    fn set_public_key(&self, username: &str, public_key: [u8; 32]) -> io::Result<()> {
+        self.public_keys
+            .write()
+            .unwrap()
+            .insert(username.to_string(), public_key);
+        Ok(())
+    }
+    fn get_public_key(&self, username: &str) -> Option<[u8; 32]> {
+        self.public_keys.read().unwrap().get(username).copied()
+    }
+    fn push_message(
+        &self,
+        recipient: &str,
+        message: InboundMessage,
+        retain: usize,
+    ) -> io::Result<()> {
+        self.note_message_id(message.id);
+        let mut mailboxes = self.messages.write().unwrap();
+        let mailbox = mailboxes.entry(recipient.to_string()).or_default();
+        mailbox.messages.push_back(message);
+        while mailbox.messages.len() > retain {
+            mailbox.pop_front();
+        }
+        Ok(())
+    }
+    fn next_message(&self, recipient: &str, device: u128) -> io::Result<Option<InboundMessage>> {
+        Ok(self
+            .advance_cursor(recipient, device)
+            .map(|(_, message)| message))
+    }
+    fn queued_messages(&self, recipient: &str) -> usize {
+        let mailboxes = self.messages.read().unwrap();
+        let Some(mailbox) = mailboxes.get(recipient) else {
+            return 0;
+        };
+        let sent = mailbox.cursors.values().max().copied().unwrap_or(0);
+        (mailbox.end() - sent.clamp(mailbox.first, mailbox.end())) as usize
+    }
+    fn forget_device(&self, username: &str, device: u128) -> io::Result<()> {
+        if let Some(mailbox) = self.messages.write().unwrap().get_mut(username) {
+            mailbox.cursors.remove(&device);
+        }
+        Ok(())
+    }
+    fn join_channel(&self, channel: &str, username: &str) -> io::Result<bool> {
+        Ok(self
+            .channels
+            .write()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_default()
+            .members
+            .insert(username.to_string()))
+    }
+    fn part_channel(&self, channel: &str, username: &str) -> io::Result<bool> {
+        let mut channels = self.channels.write().unwrap();
+        let Some(chan) = channels.get_mut(channel) else {
+            return Ok(false);
+        };
+        let removed = chan.members.remove(username);
+        if chan.members.is_empty() {
+            channels.remove(channel);
+        }
+        Ok(removed)
+    }
+    fn set_topic(&self, channel: &str, topic: Option<String>) -> io::Result<()> {
+        if let Some(chan) = self.channels.write().unwrap().get_mut(channel) {
+            chan.topic = topic;
+        }
+        Ok(())
+    }
+    fn get_channel(&self, channel: &str) -> Option<ChannelInfo> {
+        self.channels
+            .read()
+            .unwrap()
+            .get(channel)
+            .map(|chan| chan.info(channel))
+    }
+    fn list_channels(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, chan)| chan.info(name))
+            .collect()
+    }
+    fn next_message_id(&self) -> io::Result<u64> {
+        Ok(self.next_message_id.fetch_add(1, Ordering::Relaxed))
+    }
+    fn push_history(
+        &self,
+        username: &str,
+        conversation: &str,
+        message: InboundMessage,
+        retain: usize,
+    ) -> io::Result<()> {
+        self.insert_history(username, conversation, None, message, retain);
+        Ok(())
+    }
+    fn history(
+        &self,
+        username: &str,
+        conversation: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<HistoryEntry> {
+        let history = self.history.read().unwrap();
+        let Some(entries) = history
+            .get(username)
+            .and_then(|history| history.conversations.get(conversation))
+        else {
+            return Vec::new();
+        };
+        let end = match before {
+            Some(before) => entries.partition_point(|entry| entry.index < before),
+            None => entries.len(),
+        };
+        entries
+            .range(end.saturating_sub(limit)..end)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum LogEntry {
+    CreateAccount {
+        username: String,
+        pw_hash: String,
+    },
+    JoinChannel {
+        channel: String,
+        username: String,
+    },
+    PartChannel {
+        channel: String,
+        username: String,
+    },
+    SetTopic {
+        channel: String,
+        topic: Option<String>,
+    },
+    SetPassword {
+        username: String,
+        pw_hash: String,
+    },
+    SetPublicKey {
+        username: String,
+        public_key: [u8; 32],
+    },
+    PushHistory {
+        username: String,
+        conversation: String,
+        entry: HistoryEntry,
+        retain: usize,
+    },
+    /// Keeps message IDs from being reused once the messages holding them are compacted away
+    NextMessageId {
+        id: u64,
+    },
+    DeleteAccount {
+        username: String,
+    },
+    RenameAccount {
+        username: String,
+        new_username: String,
+    },
+    SetRenameRequest {
+        username: String,
+        new_username: Option<String>,
+    },
+    QueueMessage {
+        recipient: String,
+        message: InboundMessage,
+        retain: usize,
+    },
+    SetCursor {
+        recipient: String,
+        device: u128,
+        offset: u64,
+    },
+    ForgetDevice {
+        username: String,
+        device: u128,
+    },
+}
+
+/// Append-only log of every mutation, replayed into a [`MemoryStorage`] on startup and then
+/// compacted so the file only holds what is still live: accounts and their keys, queued
+/// messages with each device's cursor, channels, history and pending rename requests.
+pub struct FileStorage {
+    state: MemoryStorage,
+    log: Mutex<BufWriter<File>>,
+}
+impl FileStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let state = MemoryStorage::default();
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(path)?);
+            while !reader.fill_buf()?.is_empty() {
+                match bincode::deserialize_from::<_, LogEntry>(&mut reader) {
+                    Ok(entry) => state.apply(entry)?,
+                    // Running out of bytes means we crashed while appending the last entry,
+                    // everything before it is intact
+                    Err(err) if is_truncated(&err) => {
+                        warn!("Dropping truncated entry at the end of {}", path.display());
+                        break;
+                    }
+                    // Compacting now would throw away every entry after the corrupt one
+                    Err(err) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("corrupt entry in {}: {err}", path.display()),
+                        ))
+                    }
+                }
+            }
+        }
+
+        let mut tmp_path = PathBuf::from(path);
+        tmp_path.set_extension("tmp");
+        let mut snapshot = BufWriter::new(File::create(&tmp_path)?);
+        write_entry(
+            &mut snapshot,
+            &LogEntry::NextMessageId {
+                id: state.next_message_id.load(Ordering::Relaxed),
+            },
+        )?;
+        for (username, pw_hash) in state.accounts.read().unwrap().iter() {
+            write_entry(
+                &mut snapshot,
+                &LogEntry::CreateAccount {
+                    username: username.clone(),
+                    pw_hash: pw_hash.clone(),
+                },
+            )?;
+        }
+        for (username, public_key) in state.public_keys.read().unwrap().iter() {
+            write_entry(
+                &mut snapshot,
+                &LogEntry::SetPublicKey {
+                    username: username.clone(),
+                    public_key: *public_key,
+                },
+            )?;
+        }
+        for (recipient, mailbox) in state.messages.write().unwrap().iter_mut() {
+            for message in &mailbox.messages {
+                write_entry(
+                    &mut snapshot,
+                    &LogEntry::QueueMessage {
+                        recipient: recipient.clone(),
+                        message: message.clone(),
+                        retain: usize::MAX,
+                    },
+                )?;
+            }
+            // Offsets restart from 0 when the messages are replayed
+            for (device, offset) in &mailbox.cursors {
+                write_entry(
+                    &mut snapshot,
+                    &LogEntry::SetCursor {
+                        recipient: recipient.clone(),
+                        device: *device,
+                        offset: offset.saturating_sub(mailbox.first),
+                    },
+                )?;
+            }
+            // Cursors logged from now on have to match the offsets in the snapshot
+            mailbox.rebase();
+        }
+        for (name, chan) in state.channels.read().unwrap().iter() {
+            for username in &chan.members {
+                write_entry(
+                    &mut snapshot,
+                    &LogEntry::JoinChannel {
+                        channel: name.clone(),
+                        username: username.clone(),
+                    },
+                )?;
+            }
+            if chan.topic.is_some() {
+                write_entry(
+                    &mut snapshot,
+                    &LogEntry::SetTopic {
+                        channel: name.clone(),
+                        topic: chan.topic.clone(),
+                    },
+                )?;
+            }
+        }
+        for (username, history) in state.history.read().unwrap().iter() {
+            for (conversation, entries) in &history.conversations {
+                for entry in entries {
+                    write_entry(
+                        &mut snapshot,
+                        &LogEntry::PushHistory {
+                            username: username.clone(),
+                            conversation: conversation.clone(),
+                            entry: entry.clone(),
+                            retain: usize::MAX,
+                        },
+                    )?;
+                }
+            }
+        }
+        for request in state.rename_requests.read().unwrap().iter() {
+            write_entry(
+                &mut snapshot,
+                &LogEntry::SetRenameRequest {
+                    username: request.username.clone(),
+                    new_username: Some(request.new_username.clone()),
+                },
+            )?;
+        }
+        snapshot.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+
+        let log = OpenOptions::new().append(true).open(path)?;
+        Ok(Self {
+            state,
+            log: Mutex::new(BufWriter::new(log)),
+        })
+    }
+}
+impl Storage for FileStorage {
+    fn get_account(&self, username: &str) -> Option<String> {
+        self.state.get_account(username)
+    }
+    fn create_account(&self, username: &str, pw_hash: &str) -> io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+        if self.state.get_account(username).is_some() {
+            return Ok(false);
+        }
+        append(
+            &mut log,
+            &LogEntry::CreateAccount {
+                username: username.to_string(),
+                pw_hash: pw_hash.to_string(),
+            },
+        )?;
+        self.state.create_account(username, pw_hash)
+    }
+    fn set_password(&self, username: &str, pw_hash: &str) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::SetPassword {
+                username: username.to_string(),
+                pw_hash: pw_hash.to_string(),
+            },
+        )?;
+        self.state.set_password(username, pw_hash)
+    }
+    fn delete_account(&self, username: &str) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::DeleteAccount {
+                username: username.to_string(),
+            },
+        )?;
+        self.state.delete_account(username)
+    }
+    fn rename_account(&self, username: &str, new_username: &str) -> io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+        if self.state.get_account(username).is_none()
+            || self.state.get_account(new_username).is_some()
+        {
+            return Ok(false);
+        }
+        append(
+            &mut log,
+            &LogEntry::RenameAccount {
+                username: username.to_string(),
+                new_username: new_username.to_string(),
+            },
+        )?;
+        self.state.rename_account(username, new_username)
+    }
+    fn set_rename_request(&self, username: &str, new_username: Option<&str>) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::SetRenameRequest {
+                username: username.to_string(),
+                new_username: new_username.map(str::to_string),
+            },
+        )?;
+        self.state.set_rename_request(username, new_username)
+    }
+    fn rename_requests(&self) -> Vec<RenameRequest> {
+        self.state.rename_requests()
+    }
+    fn set_public_key(&self, username: &str, public_key: [u8; 32]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::SetPublicKey {
+                username: username.to_string(),
+                public_key,
+            },
+        )?;
+        self.state.set_public_key(username, public_key)
+    }
+    fn get_public_key(&self, username: &str) -> Option<[u8; 32]> {
+        self.state.get_public_key(username)
+    }
+    fn push_message(
+        &self,
+        recipient: &str,
+        message: InboundMessage,
+        retain: usize,
+    ) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::QueueMessage {
+                recipient: recipient.to_string(),
+                message: message.clone(),
+                retain,
+            },
+        )?;
+        self.state.push_message(recipient, message, retain)
+    }
+    fn next_message(&self, recipient: &str, device: u128) -> io::Result<Option<InboundMessage>> {
+        let mut log = self.log.lock().unwrap();
+        let Some((offset, message)) = self.state.advance_cursor(recipient, device) else {
+            return Ok(None);
+        };
+        append(
+            &mut log,
+            &LogEntry::SetCursor {
+                recipient: recipient.to_string(),
+                device,
+                offset,
+            },
+        )?;
+        Ok(Some(message))
+    }
+    fn queued_messages(&self, recipient: &str) -> usize {
+        self.state.queued_messages(recipient)
+    }
+    fn forget_device(&self, username: &str, device: u128) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::ForgetDevice {
+                username: username.to_string(),
+                device,
+            },
+        )?;
+        self.state.forget_device(username, device)
+    }
+    fn join_channel(&self, channel: &str, username: &str) -> io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+        if self.state.is_member(channel, username) {
+            return Ok(false);
+        }
+        append(
+            &mut log,
+            &LogEntry::JoinChannel {
+                channel: channel.to_string(),
+                username: username.to_string(),
+            },
+        )?;
+        self.state.join_channel(channel, username)
+    }
+    fn part_channel(&self, channel: &str, username: &str) -> io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+        if !self.state.is_member(channel, username) {
+            return Ok(false);
+        }
+        append(
+            &mut log,
+            &LogEntry::PartChannel {
+                channel: channel.to_string(),
+                username: username.to_string(),
+            },
+        )?;
+        self.state.part_channel(channel, username)
+    }
+    fn set_topic(&self, channel: &str, topic: Option<String>) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &LogEntry::SetTopic {
+                channel: channel.to_string(),
+                topic: topic.clone(),
+            },
+        )?;
+        self.state.set_topic(channel, topic)
+    }
+    fn get_channel(&self, channel: &str) -> Option<ChannelInfo> {
+        self.state.get_channel(channel)
+    }
+    fn list_channels(&self) -> Vec<ChannelInfo> {
+        self.state.list_channels()
+    }
+    fn next_message_id(&self) -> io::Result<u64> {
+        let mut log = self.log.lock().unwrap();
+        let id = self.state.next_message_id()?;
+        append(&mut log, &LogEntry::NextMessageId { id: id + 1 })?;
+        Ok(id)
+    }
+    fn push_history(
+        &self,
+        username: &str,
+        conversation: &str,
+        message: InboundMessage,
+        retain: usize,
+    ) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        // Indices are logged so they survive compaction dropping older entries
+        let entry = HistoryEntry {
+            index: self.state.next_history_index(username),
+            message,
+        };
+        append(
+            &mut log,
+            &LogEntry::PushHistory {
+                username: username.to_string(),
+                conversation: conversation.to_string(),
+                entry: entry.clone(),
+                retain,
+            },
+        )?;
+        self.state.insert_history(
+            username,
+            conversation,
+            Some(entry.index),
+            entry.message,
+            retain,
+        );
+        Ok(())
+    }
+    fn history(
+        &self,
+        username: &str,
+        conversation: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<HistoryEntry> {
+        self.state.history(username, conversation, before, limit)
+    }
+}
+
+impl MemoryStorage {
+    /// Takes the message at the cursor of `device` and returns it with the moved cursor
+    fn advance_cursor(&self, recipient: &str, device: u128) -> Option<(u64, InboundMessage)> {
+        let mut mailboxes = self.messages.write().unwrap();
+        let mailbox = mailboxes.get_mut(recipient)?;
+        let first = mailbox.first;
+        let cursor = mailbox.cursors.entry(device).or_insert(first);
+        // Messages dropped for being over the limit are skipped
+        let offset = (*cursor).max(first);
+        let message = mailbox.messages.get((offset - first) as usize)?.clone();
+        *cursor = offset + 1;
+        Some((offset + 1, message))
+    }
+    fn is_member(&self, channel: &str, username: &str) -> bool {
+        self.get_channel(channel)
+            .is_some_and(|chan| chan.members.iter().any(|member| member == username))
+    }
+    /// Makes sure IDs handed out later are greater than `id`, which was seen in the log
+    fn note_message_id(&self, id: u64) {
+        self.next_message_id.fetch_max(id + 1, Ordering::Relaxed);
+    }
+    fn next_history_index(&self, username: &str) -> u64 {
+        self.history
+            .read()
+            .unwrap()
+            .get(username)
+            .map_or(0, |history| history.next_index)
+    }
+    /// Stores `message` under `index`, or the next free index if `None`
+    fn insert_history(
+        &self,
+        username: &str,
+        conversation: &str,
+        index: Option<u64>,
+        message: InboundMessage,
+        retain: usize,
+    ) {
+        self.note_message_id(message.id);
+        let mut history = self.history.write().unwrap();
+        let history = history.entry(username.to_string()).or_default();
+        let index = index.unwrap_or(history.next_index);
+        history.next_index = history.next_index.max(index + 1);
+        let entries = history
+            .conversations
+            .entry(conversation.to_string())
+            .or_default();
+        entries.push_back(HistoryEntry { index, message });
+        while entries.len() > retain {
+            entries.pop_front();
+        }
+    }
+    fn apply(&self, entry: LogEntry) -> io::Result<()> {
+        match entry {
+            LogEntry::CreateAccount { username, pw_hash } => {
+                self.create_account(&username, &pw_hash).map(|_| ())
+            }
+            LogEntry::SetPassword { username, pw_hash } => self.set_password(&username, &pw_hash),
+            LogEntry::DeleteAccount { username } => self.delete_account(&username),
+            LogEntry::RenameAccount {
+                username,
+                new_username,
+            } => self.rename_account(&username, &new_username).map(|_| ()),
+            LogEntry::SetRenameRequest {
+                username,
+                new_username,
+            } => self.set_rename_request(&username, new_username.as_deref()),
+            LogEntry::QueueMessage {
+                recipient,
+                message,
+                retain,
+            } => self.push_message(&recipient, message, retain),
+            LogEntry::SetCursor {
+                recipient,
+                device,
+                offset,
+            } => {
+                if let Some(mailbox) = self.messages.write().unwrap().get_mut(&recipient) {
+                    mailbox.cursors.insert(device, offset);
+                }
+                Ok(())
+            }
+            LogEntry::ForgetDevice { username, device } => self.forget_device(&username, device),
+            LogEntry::NextMessageId { id } => {
+                self.note_message_id(id.saturating_sub(1));
+                Ok(())
+            }
+            LogEntry::JoinChannel { channel, username } => {
+                self.join_channel(&channel, &username).map(|_| ())
+            }
+            LogEntry::PartChannel { channel, username } => {
+                self.part_channel(&channel, &username).map(|_| ())
+            }
+            LogEntry::SetTopic { channel, topic } => self.set_topic(&channel, topic),
+            LogEntry::SetPublicKey {
+                username,
+                public_key,
+            } => self.set_public_key(&username, public_key),
+            LogEntry::PushHistory {
+                username,
+                conversation,
+                entry,
+                retain,
+            } => {
+                self.insert_history(
+                    &username,
+                    &conversation,
+                    Some(entry.index),
+                    entry.message,
+                    retain,
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Moves the value under `from` to `to`, unless `to` already holds one
+fn rename_key<V>(map: &mut HashMap<String, V>, from: &str, to: &str) {
+    if let Some(value) = map.remove(from) {
+        map.entry(to.to_string()).or_insert(value);
+    }
+}
+
+fn is_truncated(err: &bincode::Error) -> bool {
+    matches!(**err, bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof)
+}
+fn append(log: &mut BufWriter<File>, entry: &LogEntry) -> io::Result<()> {
+    write_entry(log, entry)?;
+    log.flush()
+}
+fn write_entry<W: Write>(writer: &mut W, entry: &LogEntry) -> io::Result<()> {
+    bincode::serialize_into(writer, entry).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use types::MessageBody;
+
+    fn message(id: u64, contents: &str) -> InboundMessage {
+        InboundMessage {
+            id,
+            timestamp: 0,
+            sender: "alice".to_string(),
+            recipients: vec!["bob".to_string()],
+            contents: MessageBody::Plain(contents.to_string()),
+            channel: None,
+        }
+    }
+    fn contents(message: Option<InboundMessage>) -> Option<String> {
+        match message?.contents {
+            MessageBody::Plain(contents) => Some(contents),
+            MessageBody::Sealed(_) => None,
+        }
+    }
+
+    /// A log path no other test uses, removed when dropped
+    struct TempLog(PathBuf);
+    impl TempLog {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "irc-storage-test-{}-{}.log",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn accounts_are_created_once() {
+        let storage = MemoryStorage::default();
+        assert!(storage.create_account("alice", "hash").unwrap());
+        assert!(!storage.create_account("alice", "other").unwrap());
+        assert_eq!(storage.get_account("alice").as_deref(), Some("hash"));
+        storage.set_password("alice", "new").unwrap();
+        assert_eq!(storage.get_account("alice").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn devices_have_their_own_cursor() {
+        let storage = MemoryStorage::default();
+        storage.push_message("bob", message(0, "one"), 10).unwrap();
+        storage.push_message("bob", message(1, "two"), 10).unwrap();
+        assert_eq!(storage.queued_messages("bob"), 2);
+        assert_eq!(
+            contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+            Some("one")
+        );
+        assert_eq!(storage.queued_messages("bob"), 1);
+        // A new device catches up from the oldest message still queued
+        assert_eq!(
+            contents(storage.next_message("bob", 2).unwrap()).as_deref(),
+            Some("one")
+        );
+        assert_eq!(
+            contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+            Some("two")
+        );
+        assert!(storage.next_message("bob", 1).unwrap().is_none());
+        storage.forget_device("bob", 1).unwrap();
+        assert_eq!(
+            contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+            Some("one")
+        );
+    }
+
+    #[test]
+    fn queues_drop_the_oldest_messages_beyond_retain() {
+        let storage = MemoryStorage::default();
+        for id in 0..5 {
+            storage
+                .push_message("bob", message(id, &id.to_string()), 2)
+                .unwrap();
+        }
+        assert_eq!(storage.queued_messages("bob"), 2);
+        assert_eq!(
+            contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+            Some("3")
+        );
+        // IDs seen in stored messages are never handed out again
+        assert_eq!(storage.next_message_id().unwrap(), 5);
+    }
+
+    #[test]
+    fn history_pages_go_backwards() {
+        let storage = MemoryStorage::default();
+        for id in 0..5 {
+            storage
+                .push_history("bob", "alice", message(id, ""), 10)
+                .unwrap();
+        }
+        let page = storage.history("bob", "alice", None, 2);
+        assert_eq!(
+            page.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+            [3, 4]
+        );
+        let page = storage.history("bob", "alice", Some(3), 10);
+        assert_eq!(
+            page.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn file_storage_replays_its_log() {
+        let log = TempLog::new();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            storage.create_account("bob", "hash").unwrap();
+            storage.set_public_key("bob", [7; 32]).unwrap();
+            storage.push_message("bob", message(0, "one"), 10).unwrap();
+            storage.push_message("bob", message(1, "two"), 10).unwrap();
+            storage.next_message("bob", 1).unwrap();
+            storage.join_channel("#rust", "bob").unwrap();
+            storage
+                .set_topic("#rust", Some("crabs".to_string()))
+                .unwrap();
+            storage
+                .push_history("bob", "alice", message(1, "two"), 10)
+                .unwrap();
+        }
+        let storage = FileStorage::open(&log.0).unwrap();
+        assert_eq!(storage.get_account("bob").as_deref(), Some("hash"));
+        assert_eq!(storage.get_public_key("bob"), Some([7; 32]));
+        assert_eq!(
+            contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+            Some("two")
+        );
+        assert_eq!(
+            contents(storage.next_message("bob", 2).unwrap()).as_deref(),
+            Some("one")
+        );
+        let chan = storage.get_channel("#rust").unwrap();
+        assert_eq!(chan.members, ["bob"]);
+        assert_eq!(chan.topic.as_deref(), Some("crabs"));
+        assert_eq!(storage.history("bob", "alice", None, 10).len(), 1);
+        assert_eq!(storage.next_message_id().unwrap(), 2);
+    }
+
+    #[test]
+    fn allocated_message_ids_survive_a_restart() {
+        let log = TempLog::new();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            assert_eq!(storage.next_message_id().unwrap(), 0);
+            assert_eq!(storage.next_message_id().unwrap(), 1);
+        }
+        let storage = FileStorage::open(&log.0).unwrap();
+        assert_eq!(storage.next_message_id().unwrap(), 2);
+    }
+
+    #[test]
+    fn compaction_drops_what_is_gone() {
+        let log = TempLog::new();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            storage.create_account("alice", "hash").unwrap();
+            storage.create_account("bob", "hash").unwrap();
+            for id in 0..20 {
+                storage
+                    .push_message("bob", message(id, "hello"), 10)
+                    .unwrap();
+            }
+            storage.delete_account("alice").unwrap();
+        }
+        let before = std::fs::metadata(&log.0).unwrap().len();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            assert_eq!(storage.get_account("alice"), None);
+            assert_eq!(storage.queued_messages("bob"), 10);
+        }
+        let after = std::fs::metadata(&log.0).unwrap().len();
+        assert!(
+            after < before,
+            "{} bytes after compacting {}",
+            after,
+            before
+        );
+        // Compacting again leaves the same state
+        let storage = FileStorage::open(&log.0).unwrap();
+        assert_eq!(std::fs::metadata(&log.0).unwrap().len(), after);
+        assert_eq!(storage.get_account("bob").as_deref(), Some("hash"));
+        assert_eq!(storage.queued_messages("bob"), 10);
+    }
+
+    #[test]
+    fn cursors_survive_restarts_after_messages_were_dropped() {
+        let log = TempLog::new();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            for id in 0..5 {
+                storage
+                    .push_message("bob", message(id, &id.to_string()), 2)
+                    .unwrap();
+            }
+        }
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            assert_eq!(
+                contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+                Some("3")
+            );
+        }
+        let storage = FileStorage::open(&log.0).unwrap();
+        assert_eq!(
+            contents(storage.next_message("bob", 1).unwrap()).as_deref(),
+            Some("4")
+        );
+        assert!(storage.next_message("bob", 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_last_entry_is_dropped() {
+        let log = TempLog::new();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            storage.create_account("alice", "hash").unwrap();
+            storage.create_account("bob", "hash").unwrap();
+        }
+        let len = std::fs::metadata(&log.0).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&log.0).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let storage = FileStorage::open(&log.0).unwrap();
+        assert_eq!(storage.get_account("alice").as_deref(), Some("hash"));
+        assert_eq!(storage.get_account("bob"), None);
+    }
+
+    #[test]
+    fn corrupt_entry_is_an_error() {
+        let log = TempLog::new();
+        {
+            let storage = FileStorage::open(&log.0).unwrap();
+            storage.create_account("alice", "hash").unwrap();
+        }
+        let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+        // No such variant
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+        let Err(err) = FileStorage::open(&log.0) else {
+            panic!("opened a corrupt log");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // The log is left alone for someone to look at
+        let mut reader = BufReader::new(File::open(&log.0).unwrap());
+        assert!(bincode::deserialize_from::<_, LogEntry>(&mut reader).is_ok());
+    }
+}
@@ -1,7 +1,15 @@
-use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey, rand_core, traits::PaddingScheme};
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
-use soft_aes::aes::AES_BLOCK_SIZE;
-use std::{error::Error, fmt::Write, marker::PhantomData};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes128Gcm, Nonce,
+};
+use rsa::{
+    pkcs1::EncodeRsaPublicKey, rand_core, Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey,
+    RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::marker::PhantomData;
+use thiserror::Error;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RsaData<T: Serialize + DeserializeOwned> {
@@ -9,48 +17,95 @@ pub struct RsaData<T: Serialize + DeserializeOwned> {
     pd: PhantomData<T>,
 }
 impl<T: Serialize + DeserializeOwned> RsaData<T> {
-    pub fn new(data: T, key: &RsaPublicKey) -> Result<Self, rsa::Error> {
+    pub fn new(data: T, key: &RsaPublicKey) -> Result<Self, RsaError> {
         let encrypted = key.encrypt(
             &mut rand_core::OsRng,
             Pkcs1v15Encrypt,
-            &bincode::serialize(&data).unwrap(),
+            &bincode::serialize(&data)?,
         );
         Ok(Self {
             data: encrypted?,
             pd: PhantomData,
         })
     }
-    pub fn get(&self, key: &RsaPrivateKey) -> Result<T, rsa::Error> {
-        Ok(bincode::deserialize(&key.decrypt(Pkcs1v15Encrypt, &self.data)?).unwrap())
+    pub fn get(&self, key: &RsaPrivateKey) -> Result<T, RsaError> {
+        Ok(bincode::deserialize(
+            &key.decrypt(Pkcs1v15Encrypt, &self.data)?,
+        )?)
     }
-    pub fn set(&mut self, key: &RsaPublicKey, data: T) -> Result<(), rsa::Error> {
+    pub fn set(&mut self, key: &RsaPublicKey, data: T) -> Result<(), RsaError> {
         *self = Self::new(data, key)?;
         Ok(())
     }
 }
+#[derive(Debug, Error)]
+pub enum RsaError {
+    #[error("RSA operation failed: {0}")]
+    Crypto(#[from] rsa::Error),
+    #[error("Failed to (de)serialize data: {0}")]
+    Serialization(#[from] bincode::Error),
+}
+/// Signs the bincode encoding of `data` with `key`
+pub fn sign<T: Serialize>(data: &T, key: &RsaPrivateKey) -> Result<Vec<u8>, RsaError> {
+    let digest = Sha256::digest(bincode::serialize(data)?);
+    Ok(key.sign(Pkcs1v15Sign::new::<Sha256>(), &digest)?)
+}
+/// Checks that `signature` was made over `data` by the private half of `key`
+pub fn verify<T: Serialize>(
+    data: &T,
+    signature: &[u8],
+    key: &RsaPublicKey,
+) -> Result<(), RsaError> {
+    let digest = Sha256::digest(bincode::serialize(data)?);
+    Ok(key.verify(Pkcs1v15Sign::new::<Sha256>(), &digest, signature)?)
+}
+/// SHA-256 of the PKCS#1 DER encoding of `key`, as lowercase hex
+pub fn key_fingerprint(key: &RsaPublicKey) -> String {
+    sha256::digest(key.to_pkcs1_der().unwrap().as_bytes())
+}
+/// AES-128-GCM encrypted data, each value is sealed under a fresh random nonce
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AesData<T: Serialize + DeserializeOwned> {
+    nonce: [u8; 12],
     data: Vec<u8>,
     pd: PhantomData<T>,
 }
 impl<T: Serialize + DeserializeOwned> AesData<T> {
-    pub fn new(data: T, key: &[u8]) -> Result<Self, Box<dyn Error>> {
-        let data = &bincode::serialize(&data).unwrap();
-        let encrypted = soft_aes::aes::aes_enc_cbc(data, key, &[0; AES_BLOCK_SIZE], Some("PKCS7"));
+    pub fn new(data: T, key: &[u8]) -> Result<Self, AesError> {
+        let cipher = Aes128Gcm::new_from_slice(key).map_err(|_| AesError::InvalidKey)?;
+        let nonce = Aes128Gcm::generate_nonce(&mut OsRng);
+        let data = bincode::serialize(&data)?;
+        let encrypted = cipher
+            .encrypt(&nonce, data.as_slice())
+            .map_err(|_| AesError::Encryption)?;
         Ok(Self {
-            data: encrypted.unwrap(),
+            nonce: nonce.into(),
+            data: encrypted,
             pd: PhantomData,
         })
     }
-    pub fn get(&self, key: &[u8]) -> Result<T, Box<dyn Error>> {
-        Ok(bincode::deserialize(
-            &soft_aes::aes::aes_dec_cbc(&self.data, key, &[0; AES_BLOCK_SIZE], Some("PKCS7"))
-                .unwrap(),
-        )
-        .unwrap())
+    pub fn get(&self, key: &[u8]) -> Result<T, AesError> {
+        let cipher = Aes128Gcm::new_from_slice(key).map_err(|_| AesError::InvalidKey)?;
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(&self.nonce), self.data.as_slice())
+            .map_err(|_| AesError::AuthenticationFailed)?;
+        Ok(bincode::deserialize(&decrypted)?)
     }
-    pub fn set(&mut self, key: &[u8], data: T) -> Result<(), Box<dyn Error>> {
+    pub fn set(&mut self, key: &[u8], data: T) -> Result<(), AesError> {
         *self = Self::new(data, key)?;
         Ok(())
     }
 }
+#[derive(Debug, Error)]
+pub enum AesError {
+    #[error("AES keys must be 16 bytes long")]
+    InvalidKey,
+    #[error("Failed to encrypt data")]
+    Encryption,
+    #[error(
+        "Ciphertext failed authentication, it was tampered with or encrypted under another key"
+    )]
+    AuthenticationFailed,
+    #[error("Failed to (de)serialize data: {0}")]
+    Serialization(#[from] bincode::Error),
+}
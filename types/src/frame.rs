@@ -0,0 +1,65 @@
+//! How packets are framed on the wire, shared by the client and the server. Each packet is a
+//! little-endian `u32` length followed by that many bytes of the packet's bincode encoding.
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+
+/// Bytes before each packet holding its length
+pub const HEADER_LEN: usize = 4;
+
+/// Same encoding as `bincode::serialize`, but refusing to allocate more than `limit` bytes
+fn options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(limit)
+}
+
+/// Length of the packet following `header`, or an error if it exceeds `max_len`
+pub fn packet_len(header: [u8; HEADER_LEN], max_len: u64) -> io::Result<usize> {
+    let len = u32::from_le_bytes(header);
+    if u64::from(len) > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Packet of {len} bytes exceeds maximum size"),
+        ));
+    }
+    Ok(len as usize)
+}
+/// Decodes a packet read in full, without its header
+pub fn decode<T: DeserializeOwned>(packet: &[u8]) -> io::Result<T> {
+    options(packet.len() as u64)
+        .deserialize(packet)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+/// Appends the framed encoding of `packet` to `dst`
+pub fn encode<T: Serialize>(packet: &T, dst: &mut Vec<u8>) -> io::Result<()> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "Packet too large to frame");
+    let len = options(u32::MAX.into())
+        .serialized_size(packet)
+        .map_err(|_| too_large())?;
+    let len = u32::try_from(len).map_err(|_| too_large())?;
+    dst.reserve(HEADER_LEN + len as usize);
+    dst.extend_from_slice(&len.to_le_bytes());
+    options(len.into())
+        .serialize_into(dst, packet)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Blocks until a whole packet is read from `reader`
+pub fn read<R: Read, T: DeserializeOwned>(reader: &mut R, max_len: u64) -> io::Result<T> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let mut packet = vec![0; packet_len(header, max_len)?];
+    reader.read_exact(&mut packet)?;
+    decode(&packet)
+}
+/// Writes `packet` to `writer` in one go, so packets from different threads don't interleave
+pub fn write<W: Write, T: Serialize>(writer: &mut W, packet: &T) -> io::Result<()> {
+    let mut frame = Vec::new();
+    encode(packet, &mut frame)?;
+    writer.write_all(&frame)
+}
@@ -1,49 +1,212 @@
 use enc::{AesData, RsaData};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 pub mod enc;
+pub mod frame;
+
+/// Bumped whenever the wire format changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 17;
+/// Oldest protocol version this build can still speak. Nothing is gated on the negotiated
+/// version yet, so this has to be raised along with [`PROTOCOL_VERSION`] on every bump.
+pub const MIN_PROTOCOL_VERSION: u32 = PROTOCOL_VERSION;
+/// Optional features, the server replies with the subset of the client's list it supports
+pub const CAPABILITIES: &[&str] = &[
+    "channels",
+    "push-delivery",
+    "e2e",
+    "history",
+    "receipts",
+    "presence",
+    "account-management",
+    "devices",
+];
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CPacket {
-    Handshake { client_key: rsa::RsaPublicKey },
+    /// Kept as it is since version 16, so the server can read which version any client speaks.
+    /// Nothing else about the handshake is stable: the reply gained `signature` in version 17,
+    /// and versions below [`MIN_PROTOCOL_VERSION`] are turned away, so older clients fail to
+    /// decode the reply rather than receiving `SError::UnsupportedVersion`.
+    Handshake {
+        client_key: rsa::RsaPublicKey,
+        version: u32,
+        capabilities: Vec<String>,
+    },
     Account(CAccount),
     SendMessage(CSendMessage),
     RecvMessage(CRecvMessage),
+    Channel(CChannel),
+    /// Every packet after the handshake is wrapped in one of these, `token` names the session
+    /// the request inside belongs to. The sequence number and packet are authenticated together
+    /// so a captured request can't be replayed.
+    Sequenced {
+        token: RsaData<u128>,
+        packet: AesData<SequencedPacket>,
+    },
+    Presence(CPresence),
 }
 #[derive(Serialize, Deserialize, Debug)]
+pub struct SequencedPacket {
+    /// Starts at 1 and goes up by one per request, the server rejects any number it has already
+    /// seen or that skips more than `SEQUENCE_WINDOW` ahead
+    pub seq: u64,
+    pub packet: CPacket,
+}
+pub const SEQUENCE_WINDOW: u64 = 1024;
+#[derive(Serialize, Deserialize, Debug)]
 pub enum CSendMessage {
-    Send {
-        token: RsaData<u128>,
-        message: AesData<OutboundMessage>,
-    },
+    Send { message: AesData<OutboundMessage> },
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CRecvMessage {
-    FetchNext { token: RsaData<u128> },
+    /// Asks the server to push every message queued for us as `SRecvMessage::NextMsg`
+    Subscribe,
+    /// Pages back through the messages we sent to or received from a user or channel, answered
+    /// with `SRecvMessage::History`. `before` is the index of the oldest entry already fetched.
+    History {
+        peer_or_channel: AesData<String>,
+        before: Option<u64>,
+        limit: u32,
+    },
+    /// Tells the senders of these direct messages that we read them, answered with
+    /// `SRecvMessage::Acknowledged`
+    Read { ids: AesData<Vec<u64>> },
+    /// Like `Subscribe`, but every device gets every message once, however many other devices
+    /// are logged in. `Subscribe` shares a single device between all clients using it.
+    SubscribeDevice { device: AesData<Device> },
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CAccount {
     Login {
-        token: RsaData<u128>,
         creds: AesData<Credentials>,
     },
+    /// The account has no public key until one is published with `PublishKey`
     Create {
-        token: RsaData<u128>,
         creds: AesData<Credentials>,
     },
-    Logout {
-        token: RsaData<u128>,
+    Logout,
+    /// Swaps the session token for a fresh one, answered with `SAccount::Token`
+    RefreshToken,
+    /// Replaces the public key of the logged in account
+    PublishKey {
+        public_key: [u8; 32],
+    },
+    /// Looks up the public key of a user, answered with `SAccount::PublicKey`
+    LookupKey {
+        username: AesData<String>,
+    },
+    /// Passwords are digested like `Credentials::pw_digest`. Every other session of the
+    /// account is logged out.
+    ChangePassword {
+        old: AesData<String>,
+        new: AesData<String>,
+    },
+    /// Deletes the logged in account along with its queued messages, history and channel
+    /// memberships, and ends all of its sessions
+    DeleteAccount {
+        pw_digest: AesData<String>,
+    },
+    /// Asks an admin to rename the logged in account, replacing any earlier request
+    RequestRename {
+        new_username: AesData<String>,
+    },
+    /// Admin only, answered with `SAccount::RenameRequests`
+    ListRenames,
+    /// Admin only, approving renames the account and ends all of its sessions
+    ReviewRename {
+        username: AesData<String>,
+        approve: bool,
+    },
+    /// Lists the live sessions of the logged in account, answered with `SAccount::Sessions`
+    ListSessions,
+    /// Ends another session of the logged in account, and forgets which messages its device
+    /// has been sent
+    RevokeSession {
+        id: u64,
+    },
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CPresence {
+    /// Starts pushing presence changes of these users as `SRecvMessage::Presence`, answered with
+    /// `SPresence::Watching` holding their current presence. Watches last until we go offline.
+    Watch {
+        usernames: AesData<Vec<String>>,
+    },
+    Unwatch {
+        usernames: AesData<Vec<String>>,
+    },
+    /// Marks this session as away, we show as away once all our sessions are
+    SetAway {
+        away: bool,
+    },
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CChannel {
+    Join {
+        channel: AesData<String>,
+    },
+    Part {
+        channel: AesData<String>,
+    },
+    List,
+    /// Sets the topic, or just queries it if `topic` holds `None`
+    Topic {
+        channel: AesData<String>,
+        topic: AesData<Option<String>>,
     },
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SPacket {
     Handshake {
         server_key: rsa::RsaPublicKey,
+        /// The version both sides will speak, the lower of the two peers' newest versions
+        version: u32,
+        /// Oldest version the server speaks, so a client too new for it can say which versions
+        /// would work
+        min_version: u32,
+        capabilities: Vec<String>,
         shared_key: RsaData<Vec<u8>>,
         token: RsaData<u128>,
+        /// Signature over the [`HandshakeTranscript`] by `server_key`. Checking it against the
+        /// pinned key proves the server itself encrypted the shared key and token to our key,
+        /// rather than someone in between who swapped in their own.
+        signature: Vec<u8>,
     },
     Account(SAccount),
     SendMessage(SSendMessage),
     RecvMessage(SRecvMessage),
+    Channel(SChannel),
+    /// The request could not be handled, sent in place of the usual reply
+    Error(SError),
+    Presence(SPresence),
+}
+/// What the server signs in its handshake reply
+#[derive(Serialize)]
+pub struct HandshakeTranscript<'a> {
+    /// The key the client sent, as the server received it
+    pub client_key: &'a rsa::RsaPublicKey,
+    pub version: u32,
+    pub min_version: u32,
+    pub capabilities: &'a [String],
+    pub shared_key: &'a RsaData<Vec<u8>>,
+    pub token: &'a RsaData<u128>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, thiserror::Error)]
+pub enum SError {
+    #[error("Server could not parse the packet")]
+    MalformedPacket,
+    #[error("Server could not decrypt the packet")]
+    DecryptionFailed,
+    #[error("Handshake required before any other packet")]
+    NotHandshaken,
+    #[error("Unsupported protocol version, the server speaks versions {min} to {max}")]
+    UnsupportedVersion { min: u32, max: u32 },
+    #[error("Request was replayed or arrived out of sequence")]
+    Replayed,
+    #[error("Request was not wrapped in a sequenced packet")]
+    Unsequenced,
+    #[error("Internal server error")]
+    Internal,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SAccount {
@@ -51,31 +214,202 @@ pub enum SAccount {
     AccountExists,
     IncorrectPassword,
     InvalidUsername,
+    /// Also sent for tokens that expired or belong to another connection
     InvalidToken,
     NotLoggedIn,
+    /// The replacement for a refreshed token, the old one is no longer valid
+    Token {
+        token: RsaData<u128>,
+    },
+    /// `None` if the user does not exist or never published a key
+    PublicKey {
+        public_key: Option<[u8; 32]>,
+    },
+    /// Pending rename requests, oldest first
+    RenameRequests {
+        requests: AesData<Vec<RenameRequest>>,
+    },
+    NotAdmin,
+    /// The user has no pending rename request
+    NoRenameRequest,
+    Sessions {
+        sessions: AesData<Vec<SessionInfo>>,
+    },
+    /// No other session of ours has that ID
+    NoSuchSession,
+    /// Too many accounts were created from the client's address lately, try again later
+    TooManyAccounts,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SRecvMessage {
-    NextMsg { message: AesData<InboundMessage> },
+    Subscribed,
+    /// Pushed by the server at any time once subscribed
+    NextMsg {
+        message: AesData<InboundMessage>,
+    },
+    /// Oldest first, empty once there is nothing older
+    History {
+        messages: AesData<Vec<HistoryEntry>>,
+    },
+    /// Pushed like `NextMsg` as direct messages we sent are delivered and read
+    Receipt {
+        receipt: AesData<Receipt>,
+    },
+    Acknowledged,
+    /// Pushed like `NextMsg` when a user we watch changes presence
+    Presence {
+        presence: AesData<Presence>,
+    },
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SSendMessage {
+    /// `id` is the one recipients see on the message. Direct recipients are listed with their
+    /// initial status, later changes arrive as `SRecvMessage::Receipt`s.
+    Success {
+        id: u64,
+        statuses: BTreeMap<String, DeliveryStatus>,
+    },
+    NotInChannel {
+        channel: String,
+    },
+    QueueFull {
+        recipient: String,
+    },
+    /// Sealed messages can only be sent to users, not channels
+    SealedToChannel {
+        channel: String,
+    },
+    MissingSealedBox {
+        recipient: String,
+    },
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SPresence {
+    Success,
+    /// Users that don't exist are left out
+    Watching {
+        presence: AesData<Vec<Presence>>,
+    },
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SChannel {
     Success,
+    List { channels: AesData<Vec<ChannelInfo>> },
+    Topic { topic: AesData<Option<String>> },
+    InvalidChannel,
+    NotInChannel,
+    AlreadyInChannel,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OutboundMessage {
     pub recipients: Vec<String>,
-    pub contents: String,
+    pub contents: MessageBody,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MessageBody {
+    Plain(String),
+    /// A sealed box per recipient keyed by username, encrypted to the recipient's public key so
+    /// only they can read it. Each recipient is delivered just their own box.
+    Sealed(BTreeMap<String, Vec<u8>>),
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InboundMessage {
+    /// Assigned by the server, unique and increasing in the order messages were sent
+    pub id: u64,
+    /// Milliseconds since the Unix epoch (UTC) when the server accepted the message, 0 for
+    /// messages stored before timestamps were recorded
+    pub timestamp: u64,
     pub sender: String,
     pub recipients: Vec<String>,
-    pub contents: String,
+    pub contents: MessageBody,
+    /// The channel this message was delivered through, if it was not sent to us directly
+    pub channel: Option<String>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// No such account, the message was not sent to them
+    UnknownUser,
+    Queued,
+    /// Pushed to one of the recipient's sessions
+    Delivered,
+    Read,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt {
+    /// ID of the message we sent
+    pub id: u64,
+    pub recipient: String,
+    pub status: DeliveryStatus,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Online,
+    /// Every session of the user is marked away
+    Away,
+    /// `last_seen` is milliseconds since the Unix epoch (UTC) when the user's last session
+    /// ended, `None` if they have not been seen since the server started
+    Offline {
+        last_seen: Option<u64>,
+    },
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Presence {
+    pub username: String,
+    pub status: PresenceStatus,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// Increases with every message added to a user's history
+    pub index: u64,
+    pub message: InboundMessage,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    pub members: Vec<String>,
+}
+/// Identifies one installation of a client across connections
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Device {
+    /// Random, generated once per installation
+    pub id: u128,
+    /// Shown when listing sessions, such as the host name
+    pub name: String,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInfo {
+    /// Pass to `CAccount::RevokeSession`
+    pub id: u64,
+    /// `None` until the session subscribes with `CRecvMessage::SubscribeDevice`
+    pub device: Option<Device>,
+    /// Whether this is the session that asked
+    pub current: bool,
+    /// Seconds since the session began, refreshing its token doesn't reset this
+    pub age_secs: u64,
+    pub idle_secs: u64,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenameRequest {
+    pub username: String,
+    pub new_username: String,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Credentials {
     pub username: String,
     pub pw_digest: String,
 }
+
+pub fn is_channel_name(name: &str) -> bool {
+    match name.strip_prefix('#') {
+        Some(rest) => {
+            !rest.is_empty()
+                && rest.len() <= 50
+                && rest
+                    .chars()
+                    .all(|chr| chr.is_alphanumeric() || chr == '-' || chr == '_')
+        }
+        None => false,
+    }
+}